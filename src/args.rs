@@ -0,0 +1,60 @@
+use ic_cdk::api::management_canister::bitcoin::Satoshi;
+
+use crate::network::BtcNetwork;
+use crate::*;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum ExchangeArgs {
+    Init(InitArgs),
+    Upgrade(Option<UpgradeArgs>),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct InitArgs {
+    pub orchestrator: Principal,
+    pub ii_canister: Principal,
+    pub btc_customs_principle: Principal,
+    pub network: BtcNetwork,
+    /// Defaults to the schnorr key of `network`.
+    pub key_id: Option<String>,
+    /// Defaults to `DEFAULT_TX_RECORD_RETENTION_BLOCKS`.
+    pub tx_record_retention_blocks: Option<u32>,
+}
+
+/// The fields to change on upgrade, `None` keeps the current value.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UpgradeArgs {
+    pub orchestrator: Option<Principal>,
+    pub ii_canister: Option<Principal>,
+    pub tx_record_retention_blocks: Option<u32>,
+    pub pools: Option<Vec<UpdatePoolArgs>>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct CreatePoolArgs {
+    pub rune_name: String,
+    pub gamer_register_fee: Satoshi,
+    pub claim_cooling_down: Seconds,
+    pub cookie_amount_per_claim: u128,
+    pub richswap_pool_address: String,
+    /// Defaults to the bytes of `rune_name`, it has to differ from the paths of the other pools.
+    pub key_derivation_path: Option<Vec<u8>>,
+}
+
+impl CreatePoolArgs {
+    pub fn key_derivation_path(&self) -> Vec<u8> {
+        self.key_derivation_path
+            .clone()
+            .unwrap_or(self.rune_name.clone().into_bytes())
+    }
+}
+
+/// The fields of a pool to change on upgrade, `None` keeps the current value.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct UpdatePoolArgs {
+    pub pool_address: PoolAddress,
+    pub gamer_register_fee: Option<Satoshi>,
+    pub claim_cooling_down: Option<Seconds>,
+    pub cookie_amount_per_claim: Option<u128>,
+    pub richswap_pool_address: Option<String>,
+}
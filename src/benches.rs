@@ -0,0 +1,91 @@
+use canbench_rs::{bench, bench_fn, BenchResult};
+
+use crate::args::{CreatePoolArgs, InitArgs};
+use crate::memory::{flush_state, insert_pool, mutate_pool, read_pool, set_state, POOLS};
+use crate::network::BtcNetwork;
+use crate::state::{ExchangeState, Pool, PoolState, UserAction};
+use crate::*;
+
+const POOL_STATES: u64 = 100;
+const POOL_ADDRESS: &str = "bc1pcookie";
+
+fn mock_state() -> ExchangeState {
+    ExchangeState::init(InitArgs {
+        orchestrator: Principal::anonymous(),
+        ii_canister: Principal::anonymous(),
+        btc_customs_principle: Principal::anonymous(),
+        network: BtcNetwork::Testnet4,
+        key_id: None,
+        tx_record_retention_blocks: None,
+    })
+}
+
+fn mock_pool() -> Pool {
+    let mut raw_key = vec![0x02];
+    raw_key.extend(
+        hex::decode("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap(),
+    );
+    let mut pool = Pool::new(
+        CreatePoolArgs {
+            rune_name: "COOKIE".to_string(),
+            gamer_register_fee: 10000,
+            claim_cooling_down: 60,
+            cookie_amount_per_claim: 100,
+            richswap_pool_address: "".to_string(),
+            key_derivation_path: None,
+        },
+        Pubkey::from_raw(raw_key).unwrap(),
+        POOL_ADDRESS.to_string(),
+    );
+    let txid = "0000000000000000000000000000000000000000000000000000000000000001";
+    for nonce in 0..POOL_STATES {
+        pool.commit(PoolState {
+            id: None,
+            nonce,
+            utxo: Utxo::try_from(format!("{}:{}", txid, nonce), None, 100_000).unwrap(),
+            rune_utxo: None,
+            rune_balance: 0,
+            user_action: UserAction::Register(format!("gamer_{}", nonce)),
+        });
+    }
+    pool
+}
+
+#[bench(raw)]
+fn read_pool_last_pool_state() -> BenchResult {
+    insert_pool(mock_pool());
+    bench_fn(|| {
+        read_pool(POOL_ADDRESS, |p| p.last_state().unwrap()).unwrap();
+    })
+}
+
+#[bench(raw)]
+fn mutate_pool_claim_counter() -> BenchResult {
+    insert_pool(mock_pool());
+    bench_fn(|| {
+        mutate_pool(POOL_ADDRESS, |p| {
+            p.game.claimed_cookies += 1;
+            Ok(())
+        })
+        .unwrap();
+    })
+}
+
+/// What every `mutate_pool` costs without the heap cache: decode the pool out of the stable
+/// map, then encode the whole of it back.
+#[bench(raw)]
+fn mutate_pool_through_stable_map() -> BenchResult {
+    POOLS.with_borrow_mut(|p| p.insert(POOL_ADDRESS.to_string(), mock_pool()));
+    bench_fn(|| {
+        let mut pool = POOLS.with_borrow(|p| p.get(&POOL_ADDRESS.to_string()).unwrap());
+        pool.game.claimed_cookies += 1;
+        POOLS.with_borrow_mut(|p| p.insert(POOL_ADDRESS.to_string(), pool));
+    })
+}
+
+#[bench(raw)]
+fn flush_state_on_upgrade() -> BenchResult {
+    set_state(mock_state());
+    insert_pool(mock_pool());
+    bench_fn(flush_state)
+}
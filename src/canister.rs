@@ -1,27 +1,32 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
 pub use crate::log::*;
 use crate::{
-    external::{
-        bitcoin_customs::{etching_v3, EtchingArgs},
-        internal_identity::get_principal,
-        management::request_schnorr_key,
+    external::{internal_identity::get_principal, management::request_schnorr_key},
+    event::{record_event, record_pool_event, Event, EventKind},
+    game::{
+        game::GameAndGamer,
+        gamer::{count_gamers, get_gamer},
     },
-    game::
-        game::GameAndGamer
-    ,
+    args::{CreatePoolArgs, ExchangeArgs},
+    post_game::{start_post_game_timer, PostGameJob},
+    psbt::inspect::check_outputs,
+    role::{has_role, is_orchestrator, record_admin_action, AdminAction, Role},
     memory::{
-        mutate_state, read_state, set_state, ADDRESS_PRINCIPLE_MAP, BLOCKS, GAMER, TX_RECORDS,
+        insert_pool, mutate_pool, mutate_state, pool_addresses, read_pools, read_state, set_state,
+        ADDRESS_PRINCIPLE_MAP, BLOCKS, TX_RECORDS,
     },
-    state::{ExchangeState, PoolState},
-    utils::{calculate_premine_rune_amount, tweak_pubkey_with_empty, AddLiquidityInfo, RegisterInfo},
-    ExchangeError, Seconds, MIN_BTC_VALUE,
+    state::{ExchangeState, GamePhase, Pool, PoolState},
+    utils::{get_bitcoin_network, tweak_pubkey_with_empty, AddLiquidityInfo, RegisterInfo},
+    ExchangeError, PoolAddress, MIN_BTC_VALUE,
 };
 use candid::Principal;
+use ic_canisters_http_types::{HttpRequest, HttpResponse};
 pub use ic_canister_log::log;
-use ic_cdk::{api::management_canister::bitcoin::Satoshi, init, post_upgrade, query, update};
+use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
 use ree_types::{
-    bitcoin::{Address, Network, Psbt},
+    bitcoin::{Address, Psbt},
     exchange_interfaces::{
         ExecuteTxArgs, ExecuteTxResponse, GetMinimalTxValueArgs, GetMinimalTxValueResponse,
         GetPoolInfoArgs, GetPoolInfoResponse, GetPoolListResponse, NewBlockArgs, NewBlockResponse,
@@ -31,67 +36,50 @@ use ree_types::{
 };
 
 #[init]
-fn init(
-    rune_name: String,
-    gamer_register_fee: Satoshi,
-    claim_cooling_down: Seconds,
-    cookie_amount_per_claim: u128,
-    orchestrator: Principal,
-    ii_canister: Principal,
-    btc_customs_principle: Principal,
-    richswap_pool_address: String
-) {
-    set_state(ExchangeState::init(
-        rune_name,
-        gamer_register_fee,
-        claim_cooling_down,
-        cookie_amount_per_claim,
-        orchestrator,
-        ii_canister,
-        btc_customs_principle,
-        richswap_pool_address
-    ));
-}
-
-#[update]
-pub async fn init_key() -> Result<String, ExchangeError> {
-    let (current_address, rune_name) = read_state(|s| (s.address.clone(), s.rune_name.clone()));
-    if let Some(address) = current_address {
-        return Ok(address);
-    } else {
-        let untweaked_pubkey = request_schnorr_key("key_1", rune_name.into_bytes()).await?;
-        let tweaked_pubkey = tweak_pubkey_with_empty(untweaked_pubkey.clone());
-        cfg_if::cfg_if! {
-        if #[cfg(feature = "testnet")] {
-            let address = Address::p2tr_tweaked(tweaked_pubkey, Network::Testnet4);
-        } else {
-            let address = Address::p2tr_tweaked(tweaked_pubkey, Network::Bitcoin);
-        }
+fn init(args: ExchangeArgs) {
+    match args {
+        ExchangeArgs::Init(init_args) => {
+            if let Some(blocks) = init_args.tx_record_retention_blocks {
+                crate::retention::set_tx_record_retention_blocks(blocks);
+            }
+            set_state(ExchangeState::init(init_args))
         }
-        mutate_state(|es| {
-            es.key = Some(untweaked_pubkey.clone());
-            es.address = Some(address.to_string());
-            es.game_status = es.game_status.finish_init_key();
-        });
-        Ok(address.to_string())
+        ExchangeArgs::Upgrade(_) => ic_cdk::trap("upgrade args are not allowed on init"),
     }
+    start_post_game_timer();
 }
 
-#[update]
-pub async fn init_btc_utxo(utxo_for_btc: Utxo) -> Result<(), ExchangeError> {
-    mutate_state(|es| {
-        es.states.push(PoolState {
-            id: None,
-            nonce: 0,
-            utxo: utxo_for_btc,
-            rune_utxo: None,
-            rune_balance: 0,
-            user_action: crate::state::UserAction::Init,
-        });
-
-        es.game_status = es.game_status.finish_init_btc();
-    });
+/// Derive the key of a new pool from its path and host the game at the derived address.
+#[update(guard = "is_controller")]
+pub async fn create_pool(args: CreatePoolArgs) -> Result<String, ExchangeError> {
+    let path = args.key_derivation_path();
+    // the same path derives the same address, and the same rune can't be etched twice
+    read_pools(|p| {
+        p.values()
+            .all(|pool| pool.key_derivation_path != path && pool.rune_name != args.rune_name)
+    })
+    .then(|| ())
+    .ok_or(ExchangeError::PoolAlreadyExists)?;
+    let key_id = read_state(|s| s.key_id.clone());
+    let untweaked_pubkey = request_schnorr_key(key_id, path).await?;
+    let tweaked_pubkey = tweak_pubkey_with_empty(untweaked_pubkey.clone());
+    let address = Address::p2tr_tweaked(tweaked_pubkey, get_bitcoin_network()).to_string();
+    // checked again since another pool could have been created while awaiting the key
+    read_pools(|p| !p.contains_key(&address))
+        .then(|| ())
+        .ok_or(ExchangeError::PoolAlreadyExists)?;
+    let rune_name = args.rune_name.clone();
+    insert_pool(Pool::new(args, untweaked_pubkey, address.clone()));
+    record_admin_action(format!("create_pool: {} at {}", rune_name, address));
+    record_pool_event(&address, EventKind::PoolCreated { rune_name });
+    Ok(address)
+}
 
+#[update(guard = "ensure_operator")]
+pub async fn init_btc_utxo(pool: PoolAddress, utxo_for_btc: Utxo) -> Result<(), ExchangeError> {
+    let outpoint = utxo_for_btc.outpoint();
+    mutate_pool(&pool, |p| p.init_btc_utxo(utxo_for_btc))?;
+    record_admin_action(format!("init_btc_utxo: {} of {}", outpoint, pool));
     Ok(())
 }
 
@@ -101,34 +89,29 @@ fn get_exchange_state() -> ExchangeState {
 }
 
 #[query]
-fn get_chain_key_btc_address() -> Option<String> {
-    read_state(|es| es.address.clone())
+fn get_pool(pool: PoolAddress) -> Result<Pool, ExchangeError> {
+    crate::memory::get_pool(&pool)
 }
 
 #[query]
-fn get_register_info() -> RegisterInfo {
-    let (key, address, register_fee, last_state_res) = read_state(|s| {
-        (
-            s.key.clone(),
-            s.address.clone(),
-            s.game.gamer_register_fee,
-            s.last_state(),
-        )
-    });
-    let last_state = last_state_res.unwrap();
-    let tweaked_key = tweak_pubkey_with_empty(key.clone().unwrap());
-    RegisterInfo {
-        untweaked_key: key.unwrap(),
-        address: address.unwrap(),
-        utxo: last_state.utxo.clone(),
-        register_fee,
-        tweaked_key: Pubkey::from_str(&tweaked_key.to_string()).unwrap(),
-        nonce: last_state.nonce,
-    }
+fn get_register_info(pool: PoolAddress) -> Result<RegisterInfo, ExchangeError> {
+    crate::memory::read_pool(&pool, |pool| {
+        let last_state = pool.last_state()?;
+        let tweaked_key = tweak_pubkey_with_empty(pool.key.clone());
+        Ok(RegisterInfo {
+            untweaked_key: pool.key.clone(),
+            address: pool.address.clone(),
+            utxo: last_state.utxo,
+            register_fee: pool.game.gamer_register_fee,
+            tweaked_key: Pubkey::from_str(&tweaked_key.to_string())
+                .map_err(|_| ExchangeError::ChainKeyError)?,
+            nonce: last_state.nonce,
+        })
+    })?
 }
 
 #[update]
-pub fn claim() -> Result<u128, ExchangeError> {
+pub fn claim(pool: PoolAddress) -> Result<u128, ExchangeError> {
     let principal = ic_cdk::caller();
 
     let address = crate::memory::ADDRESS_PRINCIPLE_MAP.with_borrow(|m| {
@@ -136,71 +119,85 @@ pub fn claim() -> Result<u128, ExchangeError> {
             .ok_or(ExchangeError::GamerNotFound(principal.to_text().clone()))
     })?;
 
-    mutate_state(|s| s.game.claim(address))
+    let cookies = mutate_pool(&pool, |p| p.game.claim(&pool, address.clone()))?;
+    crate::metrics::record_claim();
+    record_pool_event(
+        &pool,
+        EventKind::Claimed {
+            gamer: address,
+            cookies,
+        },
+    );
+    Ok(cookies)
 }
 
-// need permission check
-#[update]
-async fn end_game()  {
-    mutate_state(|s| {
-        s.game.is_end = true;
-        s.game_status = s.game_status.end();
-    });
+#[update(guard = "ensure_operator")]
+async fn end_game(pool: PoolAddress) -> Result<(), ExchangeError> {
+    mutate_pool(&pool, |p| p.end_game())?;
+    record_admin_action(format!("end_game: {}", pool));
+    record_pool_event(&pool, EventKind::GameEnded);
+    Ok(())
 }
 
-#[update]
-async fn etch_rune()-> std::result::Result<String, String>{
-    let (etching_args, address) = read_state(|s| {
-        (
-            EtchingArgs {
-                rune_name: s.rune_name.clone(),
-                divisibility: Some(8),
-                premine: Some(calculate_premine_rune_amount()),
-                logo: None,
-                symbol: None,
-                terms: None,
-                turbo: false,
-            },
-            s.address.clone().unwrap(),
-        )
-    });
-
-    let etch_key = etching_v3(etching_args, address)
-        .await
-        .map_err(|e| format!("etching_v3 failed: {:?}", e))?
-        .0?;
-    mutate_state(|s| {
-        s.etching_key = Some(etch_key.clone());
-    });
+#[update(guard = "ensure_operator")]
+async fn etch_rune(pool: PoolAddress) -> Result<String, ExchangeError> {
+    let etch_key = crate::post_game::etch(&pool).await?;
+    record_admin_action(format!("etch_rune: {} of {}", etch_key, pool));
 
     Ok(etch_key)
 }
 
-#[update]
-async fn update_rune_info(premine_rune_utxo: Utxo) {
-    mutate_state(|s| {
-        let rune_balance = premine_rune_utxo.maybe_rune.expect("rune not found");
-        s.rune_id = Some(rune_balance.id);
-        let mut last_state = s.states.pop().expect(
-            "No state found in the pool. Please check the pool status.",
-        );
-        last_state.rune_balance =  rune_balance.value;
-        assert_eq!(
-            last_state.rune_balance,
-            calculate_premine_rune_amount()
-        );
-        s.states.push(last_state);
-        s.game_status = s.game_status.rune_minted();
-    });
+#[update(guard = "ensure_operator")]
+async fn update_rune_info(pool: PoolAddress, premine_rune_utxo: Utxo) -> Result<(), ExchangeError> {
+    let outpoint = premine_rune_utxo.outpoint();
+    crate::post_game::update_rune_info(&pool, premine_rune_utxo).await?;
+    record_admin_action(format!("update_rune_info: {} of {}", outpoint, pool));
+    Ok(())
 }
 
 #[query]
-pub fn query_add_liquidity_info()-> AddLiquidityInfo{
-    read_state(|s| {
-        AddLiquidityInfo { 
-            btc_amount_for_add_liquidity: s.game.gamer_register_fee * GAMER.with_borrow(|g| g.len() as u64), 
-            rune_amount_for_add_liquidity: calculate_premine_rune_amount() - s.game.claimed_cookies 
-        }
+pub fn get_post_game_job(pool: PoolAddress) -> PostGameJob {
+    crate::post_game::get_post_game_job(&pool)
+}
+
+#[query]
+pub fn get_retention_config() -> crate::retention::RetentionConfig {
+    crate::retention::get_retention_config()
+}
+
+#[query]
+pub fn get_block_gaps() -> Vec<(u32, u32)> {
+    crate::reorg::get_block_gaps()
+}
+
+/// Resume the finalization held by the gap starting at `from`, for a feed which will never
+/// send the missing blocks. Only call it after checking that no tx of a hosted pool was
+/// confirmed in them, such a tx would stay pending until it is rolled back.
+#[update(guard = "ensure_operator")]
+pub fn accept_block_gap(from: u32) -> Result<(), ExchangeError> {
+    crate::reorg::accept_gap(from)?;
+    record_admin_action(format!("accept_block_gap: {}", from));
+    Ok(())
+}
+
+#[query]
+pub fn get_storage_usage() -> Vec<crate::memory::StorageUsage> {
+    crate::memory::storage_usage()
+}
+
+#[query]
+pub fn get_game_phase(pool: PoolAddress) -> Result<GamePhase, ExchangeError> {
+    crate::memory::read_pool(&pool, |pool| GamePhase {
+        next_step: pool.game_status.next_step(),
+        status: pool.game_status.clone(),
+    })
+}
+
+#[query]
+pub fn query_add_liquidity_info(pool: PoolAddress) -> Result<AddLiquidityInfo, ExchangeError> {
+    crate::memory::read_pool(&pool, |pool| AddLiquidityInfo {
+        btc_amount_for_add_liquidity: pool.game.gamer_register_fee * count_gamers(&pool.address),
+        rune_amount_for_add_liquidity: pool.premine_rune_amount() - pool.game.claimed_cookies,
     })
 }
 
@@ -212,70 +209,75 @@ fn get_minimal_tx_value(_args: GetMinimalTxValueArgs) -> GetMinimalTxValueRespon
 }
 
 #[query]
-pub fn get_pool_states() -> Vec<PoolState> {
-    read_state(|s| s.states.clone())
+pub fn get_pool_states(pool: PoolAddress) -> Result<Vec<PoolState>, ExchangeError> {
+    crate::memory::read_pool(&pool, |p| p.pool_states())
 }
 
 #[query]
 pub fn get_pool_info(args: GetPoolInfoArgs) -> GetPoolInfoResponse {
-    let pool_address = args.pool_address;
-
-    read_state(|es| match es.last_state() {
-        Ok(last_state) => pool_address
-            .eq(&es.address.clone().unwrap())
-            .then_some(PoolInfo {
-                key: es.key.clone().unwrap(),
-                key_derivation_path: vec![es.rune_name.clone().into_bytes()],
-                name: es.rune_name.clone(),
-                address: es.address.clone().unwrap(),
-                nonce: last_state.nonce,
-                coin_reserved: es
-                    .rune_id
-                    .map(|rune_id| {
-                        vec![CoinBalance {
-                            id: rune_id,
-                            value: last_state.rune_balance,
-                        }]
-                    })
-                    .unwrap_or(vec![]),
-                btc_reserved: last_state.btc_balance(),
-                utxos: last_state
-                    .rune_utxo
-                    .clone()
-                    .map(|rune_utxo| vec![rune_utxo, last_state.utxo.clone()])
-                    .unwrap_or(vec![last_state.utxo.clone()]),
-
-                attributes: "".to_string(),
-            }),
-        Err(_) => {
-            return None;
-        }
+    crate::memory::read_pool(&args.pool_address, |pool| {
+        let last_state = pool.last_state().ok()?;
+        Some(PoolInfo {
+            key: pool.key.clone(),
+            key_derivation_path: vec![pool.key_derivation_path.clone()],
+            name: pool.rune_name.clone(),
+            address: pool.address.clone(),
+            nonce: last_state.nonce,
+            coin_reserved: pool
+                .rune_id
+                .map(|rune_id| {
+                    vec![CoinBalance {
+                        id: rune_id,
+                        value: last_state.rune_balance,
+                    }]
+                })
+                .unwrap_or(vec![]),
+            btc_reserved: last_state.btc_balance(),
+            utxos: last_state
+                .rune_utxo
+                .clone()
+                .map(|rune_utxo| vec![rune_utxo, last_state.utxo.clone()])
+                .unwrap_or(vec![last_state.utxo.clone()]),
+            attributes: "".to_string(),
+        })
     })
+    .ok()?
 }
 
 #[query]
-pub fn get_game_and_gamer_infos(gamer_id: crate::Address) -> GameAndGamer {
-    read_state(|s| GameAndGamer {
-        is_end: s.game.is_end,
-        gamer_register_fee: s.game.gamer_register_fee,
-        claim_cooling_down: s.game.claim_cooling_down,
-        cookie_amount_per_claim: s.game.cookie_amount_per_claim,
-        claimed_cookies: s.game.claimed_cookies,
-        gamer: GAMER.with_borrow(|g| g.get(&gamer_id)),
+pub fn get_game_and_gamer_infos(
+    pool: PoolAddress,
+    gamer_id: crate::Address,
+) -> Result<GameAndGamer, ExchangeError> {
+    crate::memory::read_pool(&pool, |p| GameAndGamer {
+        is_end: p.game.is_end,
+        gamer_register_fee: p.game.gamer_register_fee,
+        claim_cooling_down: p.game.claim_cooling_down,
+        cookie_amount_per_claim: p.game.cookie_amount_per_claim,
+        claimed_cookies: p.game.claimed_cookies,
+        gamer: get_gamer(&pool, &gamer_id),
     })
 }
 
 #[query]
 pub fn get_pool_list() -> GetPoolListResponse {
-    let (name, address) = read_state(|s| (s.rune_name.clone(), s.address.clone().unwrap()));
-    vec![PoolBasic {
-        name: name,
-        address,
-    }]
+    read_pools(|p| {
+        p.iter()
+            .map(|(address, pool)| PoolBasic {
+                name: pool.rune_name.clone(),
+                address: address.clone(),
+            })
+            .collect()
+    })
 }
 
 #[update(guard = "ensure_orchestrator")]
 pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
+    // the response type is fixed by the REE orchestrator, so errors go over the wire as text
+    execute_intention(args).await.map_err(|e| e.to_string())
+}
+
+async fn execute_intention(args: ExecuteTxArgs) -> Result<String, ExchangeError> {
     let ExecuteTxArgs {
         psbt_hex,
         txid,
@@ -283,9 +285,18 @@ pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
         intention_index,
         zero_confirmed_tx_queue_length: _zero_confirmed_tx_queue_length,
     } = args;
-    let raw = hex::decode(&psbt_hex).map_err(|_| "invalid psbt".to_string())?;
-    let mut psbt = Psbt::deserialize(raw.as_slice()).map_err(|_| "invalid psbt".to_string())?;
-    let intention = intention_set.intentions[intention_index as usize].clone();
+    let raw = hex::decode(&psbt_hex)
+        .map_err(|e| ExchangeError::InvalidPsbt(format!("invalid psbt hex: {}", e)))?;
+    let mut psbt = Psbt::deserialize(raw.as_slice())
+        .map_err(|e| ExchangeError::InvalidPsbt(e.to_string()))?;
+    let intention = intention_set
+        .intentions
+        .get(intention_index as usize)
+        .cloned()
+        .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+            "intention index {} out of range",
+            intention_index
+        )))?;
     let initiator = intention_set.initiator_address.clone();
     let Intention {
         exchange_id: _,
@@ -299,108 +310,159 @@ pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
         output_coins,
     } = intention;
 
-    read_state(|s| {
-        return s
-            .address
-            .clone()
-            .ok_or("Exchange address not init".to_string())
-            .and_then(|address| {
-                address
-                    .eq(&pool_address)
-                    .then(|| ())
-                    .ok_or("address not match".to_string())
-            });
-    })?;
+    // route the intention to the pool it spends
+    crate::memory::read_pool(&pool_address, |_| ())?;
 
     match action.as_str() {
         "register" => {
-            let (new_state, consumed) = read_state(|es| {
-                es.validate_register(
+            // finish all the external lookups before touching the pool key
+            let principal_byte_buf = get_principal(initiator.clone())
+                .await
+                .map_err(|(code, msg)| ExchangeError::FetchPrincipalError(code, msg))?
+                .0
+                .map_err(ExchangeError::InternalIdentityResultError)?;
+            let principal_of_initiator = Principal::try_from_slice(&principal_byte_buf)
+                .map_err(|e| ExchangeError::InternalIdentityResultError(e.to_string()))?;
+            // a principal claims with a single address, shared by all the pools it plays in
+            ADDRESS_PRINCIPLE_MAP
+                .with_borrow(|m| m.get(&principal_of_initiator))
+                .filter(|address| address.ne(&initiator))
+                .map_or(Ok(()), |address| {
+                    Err(ExchangeError::PrincipalAlreadyRegistered(address))
+                })?;
+
+            // validate against the state after the lookup, it may have changed while awaiting
+            let (new_state, consumed) = crate::memory::read_pool(&pool_address, |p| {
+                p.validate_register(
                     txid.clone(),
                     nonce,
                     pool_utxo_spend,
                     pool_utxo_receive,
                     input_coins,
-                    output_coins,
+                    output_coins.clone(),
                     initiator.clone(),
                 )
-            })
-            .map_err(|e| e.to_string())?;
-            let rune_name = read_state(|s| s.rune_name.clone());
-            crate::psbt::sign(&mut psbt, &consumed, rune_name.into_bytes())
-                .await
-                .map_err(|e| e.to_string())?;
-
-            let principal_byte_buf = get_principal(initiator.clone())
-                .await
-                .map_err(|e| format!("get_principal failed: {:?}, initiator: {:?}", e, initiator))?
-                .0?;
-
-            let principal_of_initiator = Principal::from_slice(&principal_byte_buf);
-
-            mutate_state(|s| {
-                s.game.register_new_gamer(initiator.clone());
-                s.commit(new_state);
-            });
-
+            })??;
+            check_outputs(
+                &psbt,
+                txid.clone(),
+                &pool_address,
+                get_bitcoin_network(),
+                &consumed,
+                &new_state,
+                &output_coins,
+            )?;
+
+            // commit the gamer, the principal and the pool state together before signing,
+            // so that no other call could spend the same pool state while awaiting the signature
+            mutate_pool(&pool_address, |p| {
+                p.game.register_new_gamer(&pool_address, initiator.clone());
+                p.commit(new_state);
+                Ok(())
+            })?;
             ADDRESS_PRINCIPLE_MAP.with_borrow_mut(|m| {
                 m.insert(principal_of_initiator, initiator.clone());
             });
+
+            if let Err(e) = crate::psbt::sign(&mut psbt, &pool_address, &consumed).await {
+                log!(ERROR, "sign register tx {} failed: {}, rollback", txid, e);
+                rollback_unsigned(&pool_address, txid.clone());
+                return Err(e);
+            }
+            record_pool_event(
+                &pool_address,
+                EventKind::Registered {
+                    gamer: initiator.clone(),
+                    txid: txid.clone(),
+                },
+            );
         }
         "add_liquidity" => {
-            let (new_state, consumed) = read_state(|es| {
-                es.validate_add_liquidity(
+            let (new_state, consumed) = crate::memory::read_pool(&pool_address, |p| {
+                p.validate_add_liquidity(
                     txid.clone(),
                     nonce,
                     pool_utxo_spend,
                     pool_utxo_receive,
                     input_coins,
-                    output_coins,
+                    output_coins.clone(),
                     initiator.clone(),
                 )
-            })
-            .map_err(|e| e.to_string())?;
-            let rune_name = read_state(|s| s.rune_name.clone());
-            crate::psbt::sign(&mut psbt, &consumed, rune_name.into_bytes())
-                .await
-                .map_err(|e| e.to_string())?;
-            mutate_state(|s| {
-                s.game.add_liquidity();
-                s.game_status.add_liquidity();
-                s.commit(new_state);
-            });
+            })??;
+            check_outputs(
+                &psbt,
+                txid.clone(),
+                &pool_address,
+                get_bitcoin_network(),
+                &consumed,
+                &new_state,
+                &output_coins,
+            )?;
+            mutate_pool(&pool_address, |p| p.add_liquidity(new_state))?;
+            if let Err(e) = crate::psbt::sign(&mut psbt, &pool_address, &consumed).await {
+                log!(ERROR, "sign add_liquidity tx {} failed: {}, rollback", txid, e);
+                rollback_unsigned(&pool_address, txid.clone());
+                return Err(e);
+            }
+            record_pool_event(&pool_address, EventKind::LiquidityAdded { txid: txid.clone() });
         }
         "withdraw" => {
-            let (new_state, consumed) = read_state(|es| {
-                es.validate_withdraw(
+            let (new_state, consumed) = crate::memory::read_pool(&pool_address, |p| {
+                p.validate_withdraw(
                     txid.clone(),
                     nonce,
                     pool_utxo_spend,
                     pool_utxo_receive,
                     input_coins,
-                    output_coins,
+                    output_coins.clone(),
                     initiator.clone(),
                 )
-            })
-            .map_err(|e| e.to_string())?;
-            let rune_name = read_state(|s| s.rune_name.clone());
-            crate::psbt::sign(&mut psbt, &consumed, rune_name.into_bytes())
-                .await
-                .map_err(|e| e.to_string())?;
-            mutate_state(|s| {
-                s.commit(new_state);
-                s.game.withdraw(initiator.clone())
-            })
-            .map_err(|e| e.to_string())?;
+            })??;
+            check_outputs(
+                &psbt,
+                txid.clone(),
+                &pool_address,
+                get_bitcoin_network(),
+                &consumed,
+                &new_state,
+                &output_coins,
+            )?;
+            // commit before signing like register, a second withdraw validated while awaiting
+            // the signature would otherwise push another state with the same nonce
+            mutate_pool(&pool_address, |p| {
+                p.game.withdraw(&pool_address, initiator.clone())?;
+                p.commit(new_state);
+                Ok(())
+            })?;
+            if let Err(e) = crate::psbt::sign(&mut psbt, &pool_address, &consumed).await {
+                log!(ERROR, "sign withdraw tx {} failed: {}, rollback", txid, e);
+                rollback_unsigned(&pool_address, txid.clone());
+                return Err(e);
+            }
+            record_pool_event(
+                &pool_address,
+                EventKind::Withdrawn {
+                    gamer: initiator.clone(),
+                    txid: txid.clone(),
+                    cookies: get_gamer(&pool_address, &initiator)
+                        .map(|gamer| gamer.cookies)
+                        .unwrap_or_default(),
+                },
+            );
         }
         _ => {
-            return Err("invalid method".to_string());
+            return Err(ExchangeError::InvalidSignPsbtArgs(format!(
+                "invalid action: {}",
+                action
+            )));
         }
     }
 
+    // A tx rolled back before could be executed again, it is tracked like a new one
+    crate::retention::unmark_rolled_back(&txid);
     // Record the transaction as unconfirmed and track which pools it affects
     TX_RECORDS.with_borrow_mut(|m| {
-        ic_cdk::println!("new unconfirmed txid: {} in pool: {} ", txid, pool_address);
+        log!(DEBUG, "new unconfirmed txid: {} in pool: {} ", txid, pool_address);
         let mut record = m.get(&(txid.clone(), false)).unwrap_or_default();
         if !record.pools.contains(&pool_address) {
             record.pools.push(pool_address.clone());
@@ -411,13 +473,30 @@ pub async fn execute_tx(args: ExecuteTxArgs) -> ExecuteTxResponse {
     Ok(psbt.serialize_hex())
 }
 
+/// Unwind the state committed for a tx which failed to be signed, the signing error is what
+/// the orchestrator gets back so a failed rollback is only logged.
+fn rollback_unsigned(pool_address: &str, txid: crate::Txid) {
+    if let Err(e) = mutate_pool(pool_address, |p| p.rollback(txid)) {
+        log!(ERROR, "rollback unsigned txid: {} in pool: {} failed: {}", txid, pool_address, e);
+    }
+}
+
 /// REE API
 #[update(guard = "ensure_orchestrator")]
 pub fn new_block(args: NewBlockArgs) -> NewBlockResponse {
-    match crate::reorg::detect_reorg(Network::Testnet4, args.clone()) {
+    let mut fills_gap = false;
+    match crate::reorg::detect_reorg(get_bitcoin_network(), args.clone()) {
         Ok(_) => {}
+        Err(crate::reorg::ReorgError::Gap { from, to }) => {
+            crate::reorg::record_gap(from, to);
+        }
+        Err(crate::reorg::ReorgError::FillsGap { height }) => {
+            crate::reorg::fill_gap(height);
+            fills_gap = true;
+        }
         Err(crate::reorg::ReorgError::DuplicateBlock { height, hash }) => {
-            ic_cdk::println!(
+            log!(
+                INFO,
                 "Duplicate block detected at height {} with hash {}",
                 height,
                 hash
@@ -431,6 +510,7 @@ pub fn new_block(args: NewBlockArgs) -> NewBlockResponse {
         }
         Err(crate::reorg::ReorgError::Recoverable { height, depth }) => {
             crate::reorg::handle_reorg(height, depth);
+            crate::metrics::record_reorg();
         }
     }
 
@@ -444,44 +524,56 @@ pub fn new_block(args: NewBlockArgs) -> NewBlockResponse {
     // Store the new block information
     BLOCKS.with_borrow_mut(|m| {
         m.insert(block_height, args);
-        ic_cdk::println!("new block {} inserted into blocks", block_height,);
+        log!(DEBUG, "new block {} inserted into blocks", block_height);
     });
-
-    // Mark transactions as confirmed
-    for txid in confirmed_txids {
-        TX_RECORDS.with_borrow_mut(|m| {
-            if let Some(record) = m.get(&(txid.clone(), false)) {
-                m.insert((txid.clone(), true), record.clone());
-                ic_cdk::println!("confirm txid: {} with pools: {:?}", txid, record.pools);
-            }
-        });
+    if !fills_gap {
+        crate::metrics::record_block(block_height);
     }
-    // Calculate the height below which blocks are considered fully confirmed (beyond reorg risk)
-    let confirmed_height =
-        block_height - crate::reorg::get_max_recoverable_reorg_depth(Network::Testnet4) + 1;
 
-    let exchange_pool_address =
-        read_state(|s| s.address.clone()).ok_or("pool address not init".to_string())?;
+    // Mark transactions as confirmed
+    crate::retention::confirm_txs(&confirmed_txids);
+    // Calculate the height below which blocks are considered fully confirmed (beyond reorg risk),
+    // a block filling a gap is below the tip so the tip is used
+    let tip_height = BLOCKS
+        .with_borrow(|m| m.last_key_value().map(|(height, _)| height))
+        .unwrap_or(block_height);
+    let confirmed_height = (tip_height + 1)
+        .saturating_sub(crate::reorg::get_max_recoverable_reorg_depth(get_bitcoin_network()));
+    // Nothing at or above a missing block is finalized until the gap is filled
+    let finalizable_height = crate::reorg::finalizable_height(confirmed_height);
+
+    let hosted_pools = pool_addresses();
     // Finalize transactions in confirmed blocks
     BLOCKS.with_borrow(|m| {
         m.iter()
-            .take_while(|(height, _)| *height <= confirmed_height)
+            .take_while(|(height, _)| *height <= finalizable_height)
             .for_each(|(height, block_info)| {
-                ic_cdk::println!("finalizing txs in block: {}", height);
-                block_info.confirmed_txids.into_iter().for_each(|txid| {
-                    TX_RECORDS.with_borrow_mut(|m| {
-                        if let Some(record) = m.get(&(txid.clone(), true)) {
-                            record.pools.iter().for_each(|pool| {
-                                if pool.eq(&exchange_pool_address) {
-                                    mutate_state(|s| {
-                                        s.finalize(txid.clone()).map_err(|e| e.to_string())
-                                    })
-                                    .unwrap();
-                                }
-                            });
+                log!(DEBUG, "finalizing txs in block: {}", height);
+                let mut txids_by_pool: BTreeMap<PoolAddress, Vec<crate::Txid>> = BTreeMap::new();
+                TX_RECORDS.with_borrow(|m| {
+                    for txid in block_info.confirmed_txids {
+                        let Some(record) = m.get(&(txid.clone(), true)) else {
+                            continue;
+                        };
+                        crate::retention::record_finalized(height, txid.clone());
+                        for pool in record.pools {
+                            if hosted_pools.contains(&pool) {
+                                txids_by_pool.entry(pool).or_default().push(txid.clone());
+                            }
                         }
-                    })
-                })
+                    }
+                });
+                for (pool, mut txids) in txids_by_pool {
+                    // a block could confirm a chain of pool txs, they are finalized in nonce order
+                    let _ = crate::memory::read_pool(&pool, |p| {
+                        txids.sort_by_key(|txid| p.pending_nonce(txid))
+                    });
+                    for txid in txids {
+                        if let Err(e) = mutate_pool(&pool, |p| p.finalize(txid.clone())) {
+                            log!(ERROR, "finalize txid: {} in pool: {} failed: {}", txid, pool, e);
+                        }
+                    }
+                }
             })
     });
 
@@ -489,51 +581,90 @@ pub fn new_block(args: NewBlockArgs) -> NewBlockResponse {
     BLOCKS.with_borrow_mut(|m| {
         let heights_to_remove: Vec<u32> = m
             .iter()
-            .take_while(|(height, _)| *height <= confirmed_height)
+            .take_while(|(height, _)| *height <= finalizable_height)
             .map(|(height, _)| height)
             .collect();
         for height in heights_to_remove {
-            ic_cdk::println!("removing block: {}", height);
-            m.remove(&height);
+            log!(DEBUG, "removing block: {}", height);
+            if let Some(block) = m.remove(&height) {
+                record_event(EventKind::BlockFinalized {
+                    height,
+                    hash: block.block_hash,
+                });
+            }
         }
     });
 
+    // Drop the records of the txs finalized long enough ago, and the rolled back txs too
+    let retention_blocks = crate::retention::get_retention_config().tx_record_retention_blocks;
+    crate::retention::prune_finalized(confirmed_height, retention_blocks);
+    crate::retention::prune_rolled_back(confirmed_height, retention_blocks);
+
     Ok(())
 }
 
 /// REE API
 #[update(guard = "ensure_orchestrator")]
 pub fn rollback_tx(args: RollbackTxArgs) -> RollbackTxResponse {
-    // read_state(|s|
-    //     s.key.clone().ok_or("key not init".to_string())?
-    //     .eq(&args.pool_key)
-    //     .then(|| ())
-    //     .ok_or("key not match".to_string())
-    // )?;
-
-    // mutate_state(|es| es.rollback(args.txid)).map_err(|e| e.to_string())
-
-    let cookie_pool =
-        read_state(|s| s.address.clone()).ok_or("pool address not init".to_string())?;
-
-    TX_RECORDS.with_borrow(|m| {
-        let maybe_unconfirmed_record = m.get(&(args.txid.clone(), false));
-        let maybe_confirmed_record = m.get(&(args.txid.clone(), true));
-        let record = maybe_confirmed_record.or(maybe_unconfirmed_record).unwrap();
-        ic_cdk::println!(
-            "rollback txid: {} with pools: {:?}",
-            args.txid,
-            record.pools
-        );
-
-        // Roll back each affected pool to its state before this transaction
-        record.pools.iter().for_each(|pool_address| {
-            if pool_address.eq(&cookie_pool) {
-                // Rollback the state of the pool
-                mutate_state(|s| s.rollback(args.txid)).unwrap();
-            }
-        });
+    let txid = args.txid;
+
+    if crate::retention::is_rolled_back(&txid) {
+        log!(INFO, "txid: {} already rolled back", txid);
+        return Ok(());
+    }
+    let record = TX_RECORDS.with_borrow(|m| {
+        m.get(&(txid.clone(), true))
+            .or_else(|| m.get(&(txid.clone(), false)))
     });
+    let Some(record) = record else {
+        log!(WARNING, "rollback unknown txid: {}", txid);
+        return Ok(());
+    };
+    log!(INFO, "rollback txid: {} with pools: {:?}", txid, record.pools);
+    let hosted_pools: Vec<PoolAddress> = pool_addresses()
+        .into_iter()
+        .filter(|pool| record.pools.contains(pool))
+        .collect();
+    if hosted_pools.is_empty() {
+        return Ok(());
+    }
+
+    // Roll back the state of each pool together with the states built on it, a pool failing
+    // to roll back doesn't keep the others from being unwound
+    let mut unwound = vec![];
+    let mut error = None;
+    for pool in hosted_pools {
+        match mutate_pool(&pool, |p| p.rollback(txid)) {
+            Ok(txids) => {
+                for txid in txids.iter() {
+                    record_pool_event(&pool, EventKind::TxRolledBack { txid: *txid });
+                }
+                unwound.extend(txids);
+            }
+            Err(e) => {
+                log!(ERROR, "rollback txid: {} in pool: {} failed: {}", txid, pool, e);
+                error.get_or_insert(e.to_string());
+            }
+        }
+    }
+    if let Some(e) = error {
+        // keep the txid unmarked so that the orchestrator could retry the failed pools
+        unwound.retain(|t| *t != txid);
+        for txid in unwound {
+            crate::retention::mark_rolled_back(txid);
+        }
+        return Err(e);
+    }
+    if unwound.is_empty() {
+        log!(WARNING, "txid: {} has no pending state to roll back", txid);
+        record_event(EventKind::TxRolledBack { txid });
+        unwound.push(txid);
+    } else {
+        crate::metrics::record_rollback();
+    }
+    for txid in unwound {
+        crate::retention::mark_rolled_back(txid);
+    }
 
     Ok(())
 }
@@ -543,34 +674,106 @@ pub fn reset_blocks() {
     BLOCKS.with_borrow_mut(|b| {
         b.clear_new();
     });
-} 
+    crate::reorg::clear_gaps();
+    record_admin_action("reset_blocks");
+}
+
+#[update(guard = "is_controller")]
+pub fn grant_role(principal: Principal, role: Role) {
+    crate::role::grant_role(principal, role);
+    record_admin_action(format!("grant_role: {:?} to {}", role, principal));
+}
+
+#[update(guard = "is_controller")]
+pub fn revoke_role(principal: Principal, role: Role) {
+    crate::role::revoke_role(principal, role);
+    record_admin_action(format!("revoke_role: {:?} from {}", role, principal));
+}
+
+#[query]
+pub fn get_roles(principal: Principal) -> Vec<Role> {
+    crate::role::get_roles(&principal)
+}
+
+#[query]
+pub fn get_events(
+    from: Option<u64>,
+    to: Option<u64>,
+    offset: u64,
+    limit: u64,
+) -> Vec<(u64, Event)> {
+    crate::event::get_events(from, to, offset, limit)
+}
+
+#[query]
+pub fn get_gamer_events(gamer: crate::Address, offset: u64, limit: u64) -> Vec<(u64, Event)> {
+    crate::event::get_gamer_events(gamer, offset, limit)
+}
+
+#[query]
+pub fn get_tx_events(txid: crate::Txid, offset: u64, limit: u64) -> Vec<(u64, Event)> {
+    crate::event::get_tx_events(txid, offset, limit)
+}
+
+#[query]
+pub fn get_admin_actions(offset: u64, limit: u64) -> Vec<(u64, AdminAction)> {
+    crate::role::get_admin_actions(offset, limit)
+}
 
 fn is_controller() -> std::result::Result<(), String> {
-    ic_cdk::api::is_controller(&ic_cdk::caller())
-    .then(|| ())
-    .ok_or("Access denied".to_string())
+    has_role(&ic_cdk::caller(), Role::Controller)
+        .then(|| ())
+        .ok_or("Access denied".to_string())
+}
+
+fn ensure_operator() -> std::result::Result<(), String> {
+    has_role(&ic_cdk::caller(), Role::Operator)
+        .then(|| ())
+        .ok_or("Access denied".to_string())
 }
 
 fn ensure_orchestrator() -> std::result::Result<(), String> {
-    read_state(|s| {
-        s.orchestrator
-            .eq(&ic_cdk::caller())
-            .then(|| ())
-            .ok_or("Access denied".to_string())
-    })
+    is_orchestrator(&ic_cdk::caller())
+        .then(|| ())
+        .ok_or("Access denied".to_string())
+}
+
+#[query(hidden = true)]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    if req.path() == "/metrics" {
+        crate::metrics::serve_metrics()
+    } else {
+        crate::log::do_reply(req)
+    }
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    crate::memory::flush_state();
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<ExchangeArgs>) {
+    crate::memory::load_state();
+    crate::migration::migrate_stable_memory();
+    // write the current layout right away, an upgrade skipping `pre_upgrade` would otherwise
+    // decode the older layout and migrate it again
+    crate::memory::flush_state();
+    match args {
+        Some(ExchangeArgs::Upgrade(Some(upgrade_args))) => {
+            log!(INFO, "Upgrade with args: {:?}", upgrade_args);
+            mutate_state(|s| s.apply_upgrade_args(upgrade_args))
+                .unwrap_or_else(|e| ic_cdk::trap(&e.to_string()));
+        }
+        Some(ExchangeArgs::Init(_)) => ic_cdk::trap("init args are not allowed on upgrade"),
+        Some(ExchangeArgs::Upgrade(None)) | None => {}
+    }
     log!(
         INFO,
         "Finish Upgrade current version: {}",
         env!("CARGO_PKG_VERSION")
     );
-
-    mutate_state(|s| {
-        s.rune_name = "BITCOIN•TESTNET•COOKIE".to_string();
-    });
+    start_post_game_timer();
 }
 
 // Enable Candid export
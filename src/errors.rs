@@ -25,6 +25,14 @@ pub enum ExchangeError {
     FetchRuneIndexerError(RejectionCode, String),
     #[error("rune indexer result error {0}")]
     RuneIndexerResultError(String),
+    #[error("Fail to fetch principal, RejectionCode {0:?}, msg {1} ")]
+    FetchPrincipalError(RejectionCode, String),
+    #[error("internal identity result error {0}")]
+    InternalIdentityResultError(String),
+    #[error("Fail to etch rune, RejectionCode {0:?}, msg {1} ")]
+    EtchingError(RejectionCode, String),
+    #[error("bitcoin customs result error {0}")]
+    BitcoinCustomsResultError(String),
     #[error("invalid rune balance {0}")]
     ParseUtxoRuneBalanceError(String),
     #[error("invalid rune id")]
@@ -47,6 +55,14 @@ pub enum ExchangeError {
     ChainKeyError,
     #[error("invalid psbt: {0}")]
     InvalidPsbt(String),
+    #[error("psbt output mismatch: {0}")]
+    PsbtOutputMismatch(String),
+    #[error("pool output not found: {0}")]
+    PoolOutputNotFound(String),
+    #[error("invalid runestone: {0}")]
+    InvalidRunestone(String),
+    #[error("invalid address: {0}")]
+    InvalidAddress(String),
     #[error("invalid pool state: {0}")]
     InvalidState(String),
     #[error("Last state not found")]
@@ -57,6 +73,12 @@ pub enum ExchangeError {
     PoolStateExpired(u64),
     #[error("pool address not found")]
     PoolAddressNotFound,
+    #[error("pool not found: {0}")]
+    PoolNotFound(PoolAddress),
+    #[error("pool key not found")]
+    PoolKeyNotFound,
+    #[error("pool key mismatch, expected {0} but derived {1}")]
+    PoolKeyMismatch(String, String),
     #[error("Cookie balance({0}) insufficient")]
     CookieBalanceInsufficient(u128),
     #[error("Game Not End")]
@@ -69,6 +91,8 @@ pub enum ExchangeError {
     GamerWithdrawRepeatedly(Address),
     #[error("Gamer Already Exist, {0}")]
     GamerAlreadyExist(Address),
+    #[error("Principal Already Registered, with the address {0}")]
+    PrincipalAlreadyRegistered(Address),
     #[error("Gamer Cooling Down, {0} next claimable timestamp {1}")]
     GamerCoolingDown(Address, SecondTimestamp),
     #[error("Unrecoverable error")]
@@ -77,6 +101,8 @@ pub enum ExchangeError {
     DuplicateBlock(u32, String),
     #[error("Invalid block, height: {0}, depth: {1}")]
     Recoverable(u32, u32),
+    #[error("No gap of missing blocks starts at height {0}")]
+    BlockGapNotFound(u32),
 
     #[error("Reorg error: {0}")]
     ReorgError(#[from] reorg::ReorgError),
@@ -0,0 +1,290 @@
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use ree_types::CoinId;
+
+use crate::memory::{EVENTS, GAMER_EVENTS, TX_EVENTS};
+use crate::*;
+
+pub const MAX_EVENTS_PER_QUERY: u64 = 1000;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub enum EventKind {
+    Registered { gamer: Address, txid: Txid },
+    Claimed { gamer: Address, cookies: u128 },
+    GameEnded,
+    RuneEtched { etching_key: String },
+    RunesMinted { rune_id: CoinId, premine_utxo: String },
+    LiquidityAdded { txid: Txid },
+    Withdrawn { gamer: Address, txid: Txid, cookies: u128 },
+    TxRolledBack { txid: Txid },
+    TxReorged { txid: Txid },
+    BlockFinalized { height: u32, hash: String },
+    PoolCreated { rune_name: String },
+}
+
+impl EventKind {
+    fn gamer(&self) -> Option<&Address> {
+        match self {
+            EventKind::Registered { gamer, .. }
+            | EventKind::Claimed { gamer, .. }
+            | EventKind::Withdrawn { gamer, .. } => Some(gamer),
+            _ => None,
+        }
+    }
+
+    fn txid(&self) -> Option<&Txid> {
+        match self {
+            EventKind::Registered { txid, .. }
+            | EventKind::LiquidityAdded { txid }
+            | EventKind::Withdrawn { txid, .. }
+            | EventKind::TxRolledBack { txid }
+            | EventKind::TxReorged { txid } => Some(txid),
+            _ => None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct Event {
+    pub timestamp: u64,
+    /// The pool the event happened in, `None` for the events of the exchange.
+    pub pool: Option<PoolAddress>,
+    pub kind: EventKind,
+}
+
+impl Storable for Event {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Index entry pointing from a gamer address or a txid to the id of an event.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EventIndex {
+    pub key: String,
+    pub id: u64,
+}
+
+impl Storable for EventIndex {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub(crate) fn record_event(kind: EventKind) {
+    push_event(None, kind);
+}
+
+pub(crate) fn record_pool_event(pool: &str, kind: EventKind) {
+    push_event(Some(pool.to_string()), kind);
+}
+
+fn push_event(pool: Option<PoolAddress>, kind: EventKind) {
+    let event = Event {
+        timestamp: crate::utils::now_nanos(),
+        pool,
+        kind,
+    };
+    let id = EVENTS.with_borrow_mut(|e| {
+        let id = e.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        e.insert(id, event.clone());
+        id
+    });
+    if let Some(gamer) = event.kind.gamer() {
+        GAMER_EVENTS.with_borrow_mut(|g| {
+            g.insert(
+                EventIndex {
+                    key: gamer.clone(),
+                    id,
+                },
+                (),
+            )
+        });
+    }
+    if let Some(txid) = event.kind.txid() {
+        TX_EVENTS.with_borrow_mut(|t| {
+            t.insert(
+                EventIndex {
+                    key: txid.to_string(),
+                    id,
+                },
+                (),
+            )
+        });
+    }
+}
+
+fn indexed_events(
+    index: &StableBTreeMap<EventIndex, (), crate::memory::Memory>,
+    key: String,
+    offset: u64,
+    limit: u64,
+) -> Vec<(u64, Event)> {
+    let ids: Vec<u64> = index
+        .range(
+            EventIndex {
+                key: key.clone(),
+                id: 0,
+            }..,
+        )
+        .take_while(|(i, _)| i.key == key)
+        .map(|(i, _)| i.id)
+        .collect();
+    EVENTS.with_borrow(|e| {
+        ids.into_iter()
+            .rev()
+            .skip(offset as usize)
+            .take(limit.min(MAX_EVENTS_PER_QUERY) as usize)
+            .filter_map(|id| e.get(&id).map(|event| (id, event)))
+            .collect()
+    })
+}
+
+/// Events of the gamer, newest first.
+pub fn get_gamer_events(gamer: Address, offset: u64, limit: u64) -> Vec<(u64, Event)> {
+    GAMER_EVENTS.with_borrow(|g| indexed_events(g, gamer, offset, limit))
+}
+
+/// Events of the tx, newest first.
+pub fn get_tx_events(txid: Txid, offset: u64, limit: u64) -> Vec<(u64, Event)> {
+    TX_EVENTS.with_borrow(|t| indexed_events(t, txid.to_string(), offset, limit))
+}
+
+/// Events with `from <= timestamp < to` in nanoseconds, newest first.
+pub fn get_events(
+    from: Option<u64>,
+    to: Option<u64>,
+    offset: u64,
+    limit: u64,
+) -> Vec<(u64, Event)> {
+    EVENTS.with_borrow(|e| {
+        // ids are allocated in time order without gaps, so the first event at or after `to`
+        // is found by bisecting the ids
+        let first = e.first_key_value().map(|(id, _)| id).unwrap_or(0);
+        let last = e.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        let end = match to {
+            Some(to) => {
+                let (mut lo, mut hi) = (first, last);
+                while lo < hi {
+                    let mid = lo + (hi - lo) / 2;
+                    match e.get(&mid) {
+                        Some(event) if event.timestamp < to => lo = mid + 1,
+                        _ => hi = mid,
+                    }
+                }
+                lo
+            }
+            None => last,
+        };
+        e.range(..end)
+            .rev()
+            .take_while(|(_, event)| from.map_or(true, |from| event.timestamp >= from))
+            .skip(offset as usize)
+            .take(limit.min(MAX_EVENTS_PER_QUERY) as usize)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+fn clear_events() {
+    EVENTS.with_borrow_mut(|m| m.clear_new());
+    GAMER_EVENTS.with_borrow_mut(|m| m.clear_new());
+    TX_EVENTS.with_borrow_mut(|m| m.clear_new());
+}
+
+#[cfg(test)]
+fn ids(events: Vec<(u64, Event)>) -> Vec<u64> {
+    events.into_iter().map(|(id, _)| id).collect()
+}
+
+#[test]
+pub fn test_push_event_indexes_gamers_and_txids() {
+    use crate::fixtures::mock_txid;
+
+    clear_events();
+    let registered = |gamer: &str, i| EventKind::Registered {
+        gamer: gamer.to_string(),
+        txid: mock_txid(i),
+    };
+    record_pool_event("pool", registered("a", 1));
+    record_pool_event("pool", registered("ab", 2));
+    record_pool_event(
+        "pool",
+        EventKind::Claimed {
+            gamer: "a".to_string(),
+            cookies: 100,
+        },
+    );
+    record_pool_event("pool", EventKind::TxRolledBack { txid: mock_txid(1) });
+    record_event(EventKind::BlockFinalized {
+        height: 100,
+        hash: "hash_100".to_string(),
+    });
+
+    // `ab` shares a prefix with `a` but not its entries
+    assert_eq!(ids(get_gamer_events("a".to_string(), 0, 10)), vec![2, 0]);
+    assert_eq!(ids(get_gamer_events("ab".to_string(), 0, 10)), vec![1]);
+    assert!(get_gamer_events("b".to_string(), 0, 10).is_empty());
+    assert_eq!(ids(get_tx_events(mock_txid(1), 0, 10)), vec![3, 0]);
+    assert_eq!(ids(get_tx_events(mock_txid(2), 0, 10)), vec![1]);
+    assert_eq!(GAMER_EVENTS.with_borrow(|m| m.len()), 3);
+    assert_eq!(TX_EVENTS.with_borrow(|m| m.len()), 3);
+
+    let events = get_events(None, None, 0, 10);
+    assert_eq!(ids(events.clone()), vec![4, 3, 2, 1, 0]);
+    assert_eq!(events[0].1.pool, None);
+    assert_eq!(events[1].1.pool, Some("pool".to_string()));
+
+    // pages of the index, newest first
+    assert_eq!(ids(get_gamer_events("a".to_string(), 1, 10)), vec![0]);
+    assert_eq!(ids(get_gamer_events("a".to_string(), 0, 1)), vec![2]);
+    assert!(get_gamer_events("a".to_string(), 2, 10).is_empty());
+    assert_eq!(ids(get_tx_events(mock_txid(1), 1, 1)), vec![0]);
+}
+
+#[test]
+pub fn test_get_events_time_range() {
+    use crate::utils::set_test_time;
+
+    clear_events();
+    for timestamp in [10, 20, 20, 30, 40] {
+        set_test_time(timestamp);
+        record_event(EventKind::GameEnded);
+    }
+
+    assert_eq!(ids(get_events(None, None, 0, 10)), vec![4, 3, 2, 1, 0]);
+    assert_eq!(ids(get_events(Some(20), Some(40), 0, 10)), vec![3, 2, 1]);
+    assert_eq!(ids(get_events(None, Some(20), 0, 10)), vec![0]);
+    assert_eq!(ids(get_events(Some(30), None, 0, 10)), vec![4, 3]);
+    assert_eq!(ids(get_events(Some(20), Some(21), 0, 10)), vec![2, 1]);
+    assert!(get_events(Some(41), None, 0, 10).is_empty());
+    assert!(get_events(None, Some(10), 0, 10).is_empty());
+    assert!(get_events(Some(25), Some(30), 0, 10).is_empty());
+
+    // pages within the range
+    assert_eq!(ids(get_events(Some(20), None, 1, 2)), vec![3, 2]);
+    assert_eq!(ids(get_events(Some(20), None, 3, 2)), vec![1]);
+    assert!(get_events(Some(20), None, 4, 2).is_empty());
+    assert_eq!(ids(get_events(None, None, 0, 0)), Vec::<u64>::new());
+
+    // the bisection starts from the first id left after the oldest events were removed
+    EVENTS.with_borrow_mut(|m| {
+        m.remove(&0);
+        m.remove(&1);
+    });
+    assert_eq!(ids(get_events(None, Some(30), 0, 10)), vec![2]);
+    assert_eq!(ids(get_events(None, Some(41), 0, 10)), vec![4, 3, 2]);
+    assert!(get_events(None, Some(20), 0, 10).is_empty());
+}
@@ -0,0 +1,110 @@
+use std::str::FromStr;
+
+use ree_types::{exchange_interfaces::NewBlockInfo, TxRecord};
+
+use crate::args::{CreatePoolArgs, InitArgs};
+use crate::game::gamer::{insert_gamer, Gamer};
+use crate::memory::{clear_pools, ADDRESS_PRINCIPLE_MAP, BLOCKS, GAMER, TX_RECORDS};
+use crate::state::{GameStatus, Pool, PoolState, UserAction};
+use crate::*;
+
+pub(crate) fn mock_txid(i: u32) -> Txid {
+    Txid::from_str(&format!("{:064x}", i)).unwrap()
+}
+
+pub(crate) fn mock_init_args() -> InitArgs {
+    InitArgs {
+        orchestrator: Principal::anonymous(),
+        ii_canister: Principal::anonymous(),
+        btc_customs_principle: Principal::anonymous(),
+        network: crate::network::BtcNetwork::Testnet4,
+        key_id: None,
+        tx_record_retention_blocks: None,
+    }
+}
+
+pub(crate) fn mock_pubkey() -> Pubkey {
+    let mut raw = vec![0x02];
+    raw.extend(
+        hex::decode("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap(),
+    );
+    Pubkey::from_raw(raw).unwrap()
+}
+
+/// A pool at `address` whose key has been derived, waiting for its btc utxo.
+pub(crate) fn mock_pool(address: &str) -> Pool {
+    Pool::new(
+        CreatePoolArgs {
+            rune_name: "COOKIE".to_string(),
+            gamer_register_fee: 10000,
+            claim_cooling_down: 60,
+            cookie_amount_per_claim: 100,
+            richswap_pool_address: "".to_string(),
+            key_derivation_path: None,
+        },
+        mock_pubkey(),
+        address.to_string(),
+    )
+}
+
+/// The pool `pool` with a finalized init state followed by a pending state for each action,
+/// the pending state `i` is the tx `mock_txid(i)`.
+pub(crate) fn mock_pending_states(actions: Vec<UserAction>) -> Pool {
+    GAMER.with_borrow_mut(|m| m.clear_new());
+    clear_pools();
+    ADDRESS_PRINCIPLE_MAP.with_borrow_mut(|m| m.clear_new());
+
+    let mut pool = mock_pool("pool");
+    pool.game_status = GameStatus::Play;
+    let mock_state = |i: u32, user_action| PoolState {
+        id: (i > 0).then_some(mock_txid(i)),
+        nonce: i as u64,
+        utxo: Utxo::try_from(format!("{}:0", mock_txid(i)), None, 10000).unwrap(),
+        rune_utxo: None,
+        rune_balance: 0,
+        user_action,
+    };
+    pool.confirmed_state = Some(mock_state(0, UserAction::Init));
+    for (i, user_action) in actions.into_iter().enumerate() {
+        pool.commit(mock_state(i as u32 + 1, user_action));
+    }
+    pool
+}
+
+/// Register `address` with `cookies` in the pool, as the gamer of the principal `[principal]`.
+pub(crate) fn mock_gamer(pool: &mut Pool, address: &str, principal: u8, cookies: u128) {
+    let mut gamer = Gamer::new(address.to_string());
+    gamer.cookies = cookies;
+    insert_gamer(&pool.address, gamer);
+    ADDRESS_PRINCIPLE_MAP
+        .with_borrow_mut(|m| m.insert(Principal::from_slice(&[principal]), address.to_string()));
+    pool.game.claimed_cookies += cookies;
+}
+
+/// The pool `pool` with the register tx of `gamer_{i}`, holding 10 cookies, confirmed in the
+/// block `base + i` for each of the `n` blocks after `base`.
+pub(crate) fn mock_chain(base: u32, n: u32) -> Pool {
+    BLOCKS.with_borrow_mut(|m| m.clear_new());
+    TX_RECORDS.with_borrow_mut(|m| m.clear_new());
+
+    let gamers: Vec<String> = (1..=n).map(|i| format!("gamer_{}", i)).collect();
+    let mut pool =
+        mock_pending_states(gamers.iter().cloned().map(UserAction::Register).collect());
+    for (i, gamer) in (1..=n).zip(gamers.iter()) {
+        mock_gamer(&mut pool, gamer, i as u8, 10);
+        let mut record = TxRecord::default();
+        record.pools.push("pool".to_string());
+        TX_RECORDS.with_borrow_mut(|m| m.insert((mock_txid(i), true), record));
+        BLOCKS.with_borrow_mut(|m| m.insert(base + i, mock_block(base + i, vec![mock_txid(i)])));
+    }
+    pool
+}
+
+pub(crate) fn mock_block(height: u32, confirmed_txids: Vec<Txid>) -> NewBlockInfo {
+    NewBlockInfo {
+        block_height: height,
+        block_hash: format!("hash_{}", height),
+        block_timestamp: 0,
+        confirmed_txids,
+    }
+}
@@ -1,4 +1,3 @@
-use crate::memory::GAMER;
 use crate::*;
 use crate::{
     utils::get_chain_second_timestamp, Address, ExchangeError,
@@ -7,7 +6,7 @@ use crate::{
 use ic_cdk::api::management_canister::bitcoin::Satoshi;
 use serde::{Deserialize, Serialize};
 
-use super::gamer::Gamer;
+use super::gamer::{get_gamer, insert_gamer, Gamer};
 
 #[derive(CandidType, Deserialize, Serialize, Clone)]
 pub struct Game {
@@ -39,8 +38,8 @@ impl Game {
         }
     }
 
-    pub fn register_new_gamer(&mut self, gamer_id: Address) {
-        GAMER.with_borrow_mut(|g| g.insert(gamer_id.clone(), Gamer::new(gamer_id.clone())));
+    pub fn register_new_gamer(&mut self, pool: &str, gamer_id: Address) {
+        insert_gamer(pool, Gamer::new(gamer_id));
     }
 
     pub fn is_end(&self) -> bool {
@@ -51,16 +50,13 @@ impl Game {
         self.already_add_liquidity = true;
     }
 
-    pub fn able_claim(&self, gamer_id: Address) -> Result<()> {
+    pub fn able_claim(&self, pool: &str, gamer_id: Address) -> Result<()> {
         if self.is_end() {
             return Err(ExchangeError::GameEnd);
         }
 
-        GAMER
-            .with_borrow(|g| {
-                g.get(&gamer_id)
-                    .ok_or(ExchangeError::GamerNotFound(gamer_id.clone()))
-            })
+        get_gamer(pool, &gamer_id)
+            .ok_or(ExchangeError::GamerNotFound(gamer_id.clone()))
             .and_then(|gamer| {
                 if get_chain_second_timestamp() > gamer.last_click_time + self.claim_cooling_down {
                     Ok(())
@@ -73,13 +69,11 @@ impl Game {
             })
     }
 
-    pub fn claim(&mut self, gamer_id: Address) -> Result<u128> {
-        self.able_claim(gamer_id.clone())?;
+    pub fn claim(&mut self, pool: &str, gamer_id: Address) -> Result<u128> {
+        self.able_claim(pool, gamer_id.clone())?;
 
-        let mut gamer = GAMER.with_borrow(|g| {
-            g.get(&gamer_id)
-                .ok_or(ExchangeError::GamerNotFound(gamer_id.clone()))
-        })?;
+        let mut gamer =
+            get_gamer(pool, &gamer_id).ok_or(ExchangeError::GamerNotFound(gamer_id.clone()))?;
 
         self.claimed_cookies = self.claimed_cookies
             .checked_add(self.cookie_amount_per_claim)
@@ -87,27 +81,21 @@ impl Game {
         gamer.claim(self.cookie_amount_per_claim)?;
 
         let new_cookies_balance = gamer.cookies;
-        GAMER.with_borrow_mut(
-            |g| g.insert(gamer_id.clone(), gamer.clone()),
-        );
+        insert_gamer(pool, gamer);
 
         Ok(new_cookies_balance)
     }
 
-    pub fn withdraw(&mut self, gamer_id: Address) -> Result<u128> {
-        let mut gamer = GAMER.with_borrow(|g| {
-            g.get(&gamer_id)
-                .ok_or(ExchangeError::GamerNotFound(gamer_id.clone()))
-        })?;
+    pub fn withdraw(&mut self, pool: &str, gamer_id: Address) -> Result<u128> {
+        let mut gamer =
+            get_gamer(pool, &gamer_id).ok_or(ExchangeError::GamerNotFound(gamer_id.clone()))?;
 
         if self.is_end() {
             if !gamer.is_withdrawn {
                 gamer.is_withdrawn = true;
                 let cookies = gamer.cookies;
 
-                GAMER.with_borrow_mut(
-                    |g| g.insert(gamer_id.clone(), gamer.clone()),
-                );
+                insert_gamer(pool, gamer);
 
                 Ok(cookies)
             } else {
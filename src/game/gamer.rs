@@ -1,8 +1,8 @@
-use std::borrow::Cow;
 
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::Storable;
 
+use crate::memory::{pool_addresses, GAMER};
 use crate::utils::get_chain_second_timestamp;
 use crate::*;
 use crate::SecondTimestamp;
@@ -18,11 +18,11 @@ pub struct Gamer {
 
 impl Storable for Gamer {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(bincode::serialize(self).unwrap())
+        crate::migration::encode(self)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        bincode::deserialize(bytes.as_ref()).unwrap()
+        crate::migration::decode(bytes.as_ref())
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -45,4 +45,60 @@ impl Gamer {
         self.last_click_time = get_chain_second_timestamp();
         Ok(self.cookies)
     }
-}
\ No newline at end of file
+}
+
+/// A gamer is registered per pool, the same address could play in several pools.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GamerKey {
+    pub pool: PoolAddress,
+    pub gamer: Address,
+}
+
+impl GamerKey {
+    pub fn new(pool: &str, gamer: &str) -> Self {
+        Self {
+            pool: pool.to_string(),
+            gamer: gamer.to_string(),
+        }
+    }
+}
+
+impl Storable for GamerKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        std::borrow::Cow::Owned(bincode::serialize(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        bincode::deserialize(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn get_gamer(pool: &str, gamer: &str) -> Option<Gamer> {
+    GAMER.with_borrow(|g| g.get(&GamerKey::new(pool, gamer)))
+}
+
+pub(crate) fn insert_gamer(pool: &str, gamer: Gamer) {
+    GAMER.with_borrow_mut(|g| g.insert(GamerKey::new(pool, &gamer.address), gamer));
+}
+
+pub(crate) fn remove_gamer(pool: &str, gamer: &str) -> Option<Gamer> {
+    GAMER.with_borrow_mut(|g| g.remove(&GamerKey::new(pool, gamer)))
+}
+
+/// Number of the gamers registered in `pool`.
+pub fn count_gamers(pool: &str) -> u64 {
+    GAMER.with_borrow(|g| {
+        g.range(GamerKey::new(pool, "")..)
+            .take_while(|(key, _)| key.pool == pool)
+            .count() as u64
+    })
+}
+
+/// Whether `gamer` is registered in any of the pools.
+pub fn is_registered(gamer: &str) -> bool {
+    pool_addresses()
+        .iter()
+        .any(|pool| GAMER.with_borrow(|g| g.contains_key(&GamerKey::new(pool, gamer))))
+}
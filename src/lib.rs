@@ -1,13 +1,25 @@
+pub mod args;
+#[cfg(feature = "canbench-rs")]
+mod benches;
 pub mod canister;
 pub mod errors;
+pub mod event;
 pub mod external;
+#[cfg(test)]
+mod fixtures;
 pub mod game;
 pub mod memory;
+pub mod metrics;
+pub mod migration;
+pub mod network;
 pub mod state;
 pub mod utils;
 pub mod log;
+pub mod post_game;
 pub mod psbt;
 pub mod reorg;
+pub mod retention;
+pub mod role;
 
 pub use candid::{Principal, CandidType};
 pub use errors::*;
@@ -26,4 +38,6 @@ pub type Seconds = u64;
 pub type SecondTimestamp = u64;
 pub type PoolId = Pubkey;
 pub type Address = String;
+pub type PoolAddress = String;
 pub const MIN_BTC_VALUE: u64 = 10000;
+pub const MIN_ETCHING_CONFIRMATIONS: u32 = 4;
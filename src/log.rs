@@ -10,7 +10,7 @@ declare_log_buffer!(name = WARNING, capacity = 1000);
 declare_log_buffer!(name = ERROR, capacity = 1000);
 declare_log_buffer!(name = CRITICAL, capacity = 1000);
 
-#[derive(Clone, serde::Serialize, Deserialize, Debug, Copy)]
+#[derive(Clone, serde::Serialize, Deserialize, Debug, Copy, PartialEq, Eq)]
 pub enum Priority {
     DEBUG,
     INFO,
@@ -19,6 +19,21 @@ pub enum Priority {
     CRITICAL,
 }
 
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "DEBUG" => Ok(Priority::DEBUG),
+            "INFO" => Ok(Priority::INFO),
+            "WARNING" => Ok(Priority::WARNING),
+            "ERROR" => Ok(Priority::ERROR),
+            "CRITICAL" => Ok(Priority::CRITICAL),
+            _ => Err(format!("unknown priority {}", s)),
+        }
+    }
+}
+
 #[derive(Clone, serde::Serialize, Deserialize, Debug)]
 pub struct LogEntry {
     pub canister_id: String,
@@ -56,7 +71,7 @@ pub fn do_reply(req: HttpRequest) -> HttpResponse {
                 Ok(value) => value,
                 Err(_) => {
                     return HttpResponseBuilder::bad_request()
-                        .with_body_and_content_length("failed to parse the 'time' parameter")
+                        .with_body_and_content_length("failed to parse the 'limit' parameter")
                         .build()
                 }
             },
@@ -68,22 +83,37 @@ pub fn do_reply(req: HttpRequest) -> HttpResponse {
                 Ok(value) => value,
                 Err(_) => {
                     return HttpResponseBuilder::bad_request()
-                        .with_body_and_content_length("failed to parse the 'time' parameter")
+                        .with_body_and_content_length("failed to parse the 'offset' parameter")
                         .build()
                 }
             },
             None => 0,
         };
 
+        let priority = match req.raw_query_param("priority") {
+            Some(arg) => match Priority::from_str(arg) {
+                Ok(value) => Some(value),
+                Err(_) => {
+                    return HttpResponseBuilder::bad_request()
+                        .with_body_and_content_length("failed to parse the 'priority' parameter")
+                        .build()
+                }
+            },
+            None => None,
+        };
+        let file = req.raw_query_param("file");
+
         let mut entries: Log = Default::default();
         merge_log(&mut entries, &DEBUG, Priority::DEBUG);
         merge_log(&mut entries, &INFO, Priority::INFO);
         merge_log(&mut entries, &WARNING, Priority::WARNING);
         merge_log(&mut entries, &ERROR, Priority::ERROR);
         merge_log(&mut entries, &CRITICAL, Priority::CRITICAL);
-        entries
-            .entries
-            .retain(|entry| entry.timestamp >= max_skip_timestamp);
+        entries.entries.retain(|entry| {
+            entry.timestamp >= max_skip_timestamp
+                && priority.map_or(true, |p| p == entry.priority)
+                && file.map_or(true, |f| entry.file.contains(f))
+        });
         entries
             .entries
             .sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
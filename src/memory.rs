@@ -1,36 +1,65 @@
 pub(crate) use std::cell::RefCell;
+use std::collections::BTreeMap;
 
 use candid::Principal;
-use ic_stable_structures::{memory_manager::{MemoryId, MemoryManager, VirtualMemory}, Cell, DefaultMemoryImpl, StableBTreeMap};
+use ic_stable_structures::{memory_manager::{MemoryId, MemoryManager, VirtualMemory}, Cell, DefaultMemoryImpl, Memory as _, StableBTreeMap};
 use ree_types::{exchange_interfaces::NewBlockInfo, TxRecord, Txid};
 
-use crate::{game::gamer::Gamer, state::ExchangeState, Address};
+use crate::{
+    event::{Event, EventIndex},
+    game::gamer::{Gamer, GamerKey},
+    metrics::MetricCounters,
+    post_game::PostGameJob,
+    retention::RetentionConfig,
+    role::{AdminAction, Roles},
+    state::{ExchangeState, Pool, StoredState},
+    Address, CandidType, Deserialize, ExchangeError, PoolAddress, Result,
+};
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 const STATE_MEMORY_ID: MemoryId = MemoryId::new(1);
-const GAMERS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const LEGACY_GAMERS_MEMORY_ID: MemoryId = MemoryId::new(2);
 const ADDRESS_PRINCIPAL_MAP_MEMORY_ID: MemoryId = MemoryId::new(3);
 const BLOCKS_MEMORY_ID: MemoryId = MemoryId::new(4);
 const TX_RECORDS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ROLES_MEMORY_ID: MemoryId = MemoryId::new(6);
+const ADMIN_ACTIONS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const LEGACY_POST_GAME_JOB_MEMORY_ID: MemoryId = MemoryId::new(8);
+const METRICS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const EVENTS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const GAMER_EVENTS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const TX_EVENTS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const RETENTION_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(13);
+const FINALIZED_TXS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const BLOCK_GAPS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const ROLLED_BACK_TXS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const POOLS_MEMORY_ID: MemoryId = MemoryId::new(17);
+const GAMERS_MEMORY_ID: MemoryId = MemoryId::new(18);
+const POST_GAME_JOBS_MEMORY_ID: MemoryId = MemoryId::new(19);
+const ROLLED_BACK_HEIGHTS_MEMORY_ID: MemoryId = MemoryId::new(20);
+
+pub(crate) const WASM_PAGE_SIZE_IN_BYTES: u64 = 64 * 1024;
 
 thread_local! {
 
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
     RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
-    pub static STATE: RefCell<Cell<Option<ExchangeState>, Memory>> = RefCell::new(
+    pub static STATE: RefCell<Cell<Option<StoredState>, Memory>> = RefCell::new(
         Cell::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(STATE_MEMORY_ID)), 
             Option::None
         ).expect("state memory not initialized")
     );
 
-    pub static GAMER: RefCell<StableBTreeMap<Address, Gamer, Memory>> = RefCell::new(
+    /// The gamers of the single pool hosted before the pools were keyed by address, moved
+    /// into `GAMER` by `migrate_stable_memory`.
+    pub static LEGACY_GAMER: RefCell<StableBTreeMap<Address, Gamer, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(GAMERS_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(LEGACY_GAMERS_MEMORY_ID)),
         )
-    ); 
+    );
 
     pub static ADDRESS_PRINCIPLE_MAP: RefCell<StableBTreeMap<Principal, Address, Memory>> = RefCell::new(
         StableBTreeMap::init(
@@ -50,11 +79,113 @@ thread_local! {
         )
     );
 
-}
+    pub static ROLES: RefCell<StableBTreeMap<Principal, Roles, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ROLES_MEMORY_ID)),
+        )
+    );
+
+    pub static ADMIN_ACTIONS: RefCell<StableBTreeMap<u64, AdminAction, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ADMIN_ACTIONS_MEMORY_ID)),
+        )
+    );
+
+    /// The working copy of the state, `STATE` is only written in `pre_upgrade`.
+    static STATE_CACHE: RefCell<Option<ExchangeState>> = RefCell::new(None);
+
+    /// The post game job of the single pool hosted before the pools were keyed by address,
+    /// moved into `POST_GAME_JOBS` by `migrate_stable_memory`.
+    pub static LEGACY_POST_GAME_JOB: RefCell<Cell<PostGameJob, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(LEGACY_POST_GAME_JOB_MEMORY_ID)),
+            PostGameJob::default()
+        ).expect("post game job memory not initialized")
+    );
+
+    pub static METRICS: RefCell<Cell<MetricCounters, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(METRICS_MEMORY_ID)),
+            MetricCounters::default()
+        ).expect("metrics memory not initialized")
+    );
+
+    pub static EVENTS: RefCell<StableBTreeMap<u64, Event, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(EVENTS_MEMORY_ID)),
+        )
+    );
+
+    pub static GAMER_EVENTS: RefCell<StableBTreeMap<EventIndex, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(GAMER_EVENTS_MEMORY_ID)),
+        )
+    );
+
+    pub static TX_EVENTS: RefCell<StableBTreeMap<EventIndex, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(TX_EVENTS_MEMORY_ID)),
+        )
+    );
+
+    pub static RETENTION_CONFIG: RefCell<Cell<RetentionConfig, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(RETENTION_CONFIG_MEMORY_ID)),
+            RetentionConfig::default()
+        ).expect("retention config memory not initialized")
+    );
+
+    /// The txids finalized by block height, to prune their records once they are old enough.
+    pub static FINALIZED_TXS: RefCell<StableBTreeMap<(u32, Txid), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(FINALIZED_TXS_MEMORY_ID)),
+        )
+    );
+
+    /// The ranges of the heights skipped by the block feed, `from` to `to` inclusive.
+    pub static BLOCK_GAPS: RefCell<StableBTreeMap<u32, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(BLOCK_GAPS_MEMORY_ID)),
+        )
+    );
+
+    /// The txids rolled back by the orchestrator, their records are removed from `TX_RECORDS`.
+    /// They are kept for as many blocks as the records of the finalized txs.
+    pub static ROLLED_BACK_TXS: RefCell<StableBTreeMap<Txid, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ROLLED_BACK_TXS_MEMORY_ID)),
+        )
+    );
+
+    /// The working copy of the pools, `POOLS` is only written in `pre_upgrade`.
+    static POOLS_CACHE: RefCell<BTreeMap<PoolAddress, Pool>> = RefCell::new(BTreeMap::new());
+
+    /// The txids in `ROLLED_BACK_TXS` by the tip height they were rolled back at, to forget
+    /// them once they are old enough.
+    pub static ROLLED_BACK_HEIGHTS: RefCell<StableBTreeMap<(u32, Txid), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(ROLLED_BACK_HEIGHTS_MEMORY_ID)),
+        )
+    );
+
+    pub static POOLS: RefCell<StableBTreeMap<PoolAddress, Pool, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(POOLS_MEMORY_ID)),
+        )
+    );
+
+    pub static GAMER: RefCell<StableBTreeMap<GamerKey, Gamer, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(GAMERS_MEMORY_ID)),
+        )
+    );
+
+    pub static POST_GAME_JOBS: RefCell<StableBTreeMap<PoolAddress, PostGameJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(POST_GAME_JOBS_MEMORY_ID)),
+        )
+    );
 
-pub fn init_gamer() -> StableBTreeMap<Address, Gamer, Memory> {
-    // StableBTreeMap::init(with_memory_manager(|m| m.get(GAMERS_MEMORY_ID)))
-    StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(GAMERS_MEMORY_ID)))
 }
 
 // pub fn init_address_principal_map() -> StableBTreeMap<Principal, Address, Memory> {
@@ -63,14 +194,39 @@ pub fn init_gamer() -> StableBTreeMap<Address, Gamer, Memory> {
 // }
 
 pub fn get_state() -> ExchangeState {
-    STATE.with(|c| c.borrow().get().clone().unwrap())
+    read_state(|s| s.clone())
 }
 
 pub fn set_state(state: ExchangeState) {
-    STATE.with(|c| {
-        c.borrow_mut()
-            .set(Some(state))
-            .expect("Failed to set SETTINGS.")
+    STATE_CACHE.with_borrow_mut(|s| *s = Some(state));
+}
+
+/// Load the state and the pools from stable memory into the heap, called in `post_upgrade`.
+pub fn load_state() {
+    let state = STATE.with_borrow(|c| c.get().as_ref().map(|s| s.state.clone()));
+    STATE_CACHE.with_borrow_mut(|s| *s = state);
+    let pools = POOLS.with_borrow(|p| p.iter().collect());
+    POOLS_CACHE.with_borrow_mut(|p| *p = pools);
+}
+
+/// Write the heap state and pools back to stable memory, called in `pre_upgrade`.
+pub fn flush_state() {
+    let state = STATE_CACHE.with_borrow(|s| s.clone()).map(|state| StoredState {
+        state,
+        legacy_pool: None,
+    });
+    STATE.with_borrow_mut(|c| c.set(state).expect("Failed to set STATE."));
+    POOLS_CACHE.with_borrow(|cache| {
+        POOLS.with_borrow_mut(|p| {
+            let removed: Vec<PoolAddress> =
+                p.iter().map(|(address, _)| address).filter(|a| !cache.contains_key(a)).collect();
+            for address in removed {
+                p.remove(&address);
+            }
+            for (address, pool) in cache.iter() {
+                p.insert(address.clone(), pool.clone());
+            }
+        })
     });
 }
 
@@ -78,17 +234,124 @@ pub fn mutate_state<F, R>(f: F) -> R
 where 
     F: FnOnce(&mut ExchangeState)->R
 {
-    let mut state = get_state();
-    let r = f(&mut state);
-    set_state(state);
-    r
+    STATE_CACHE.with_borrow_mut(|s| f(s.as_mut().expect("State not initialized!")))
 }
 
 pub fn read_state<F, R>(f: F) -> R
 where
     F: FnOnce(&ExchangeState) -> R,
 {
-    let state = get_state();
-    let r = f(&state);
+    STATE_CACHE.with_borrow(|s| f(s.as_ref().expect("State not initialized!")))
+}
+
+pub fn get_pool(address: &str) -> Result<Pool> {
+    read_pool(address, |p| p.clone())
+}
+
+pub fn read_pool<F, R>(address: &str, f: F) -> Result<R>
+where
+    F: FnOnce(&Pool) -> R,
+{
+    POOLS_CACHE
+        .with_borrow(|p| p.get(address).map(f))
+        .ok_or(ExchangeError::PoolNotFound(address.to_string()))
+}
+
+pub fn read_pools<F, R>(f: F) -> R
+where
+    F: FnOnce(&BTreeMap<PoolAddress, Pool>) -> R,
+{
+    POOLS_CACHE.with_borrow(f)
+}
+
+pub fn pool_addresses() -> Vec<PoolAddress> {
+    POOLS_CACHE.with_borrow(|p| p.keys().cloned().collect())
+}
+
+pub(crate) fn insert_pool(pool: Pool) {
+    POOLS_CACHE.with_borrow_mut(|p| p.insert(pool.address.clone(), pool));
+}
+
+/// Apply `f` to the pool at `address` in place. The pool is out of the cache while `f` runs,
+/// so `f` could still read the other pools, and it must check everything before it changes
+/// the pool since the pool is put back as it is even when `f` fails.
+pub fn mutate_pool<F, R>(address: &str, f: F) -> Result<R>
+where
+    F: FnOnce(&mut Pool) -> Result<R>,
+{
+    let mut pool = POOLS_CACHE
+        .with_borrow_mut(|p| p.remove(address))
+        .ok_or(ExchangeError::PoolNotFound(address.to_string()))?;
+    let r = f(&mut pool);
+    POOLS_CACHE.with_borrow_mut(|p| p.insert(address.to_string(), pool));
     r
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) fn clear_pools() {
+    POOLS_CACHE.with_borrow_mut(|p| p.clear());
+    POOLS.with_borrow_mut(|p| p.clear_new());
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StorageUsage {
+    pub name: String,
+    pub entries: u64,
+    /// Size of the virtual memory allocated to the structure.
+    pub bytes: u64,
+}
+
+fn memory_size_bytes(id: MemoryId) -> u64 {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id).size()) * WASM_PAGE_SIZE_IN_BYTES
+}
+
+/// Number of the entries and the memory allocated for each stable structure.
+pub fn storage_usage() -> Vec<StorageUsage> {
+    let usage = |name: &str, id: MemoryId, entries: u64| StorageUsage {
+        name: name.to_string(),
+        entries,
+        bytes: memory_size_bytes(id),
+    };
+    vec![
+        usage("state", STATE_MEMORY_ID, STATE.with_borrow(|c| c.get().is_some() as u64)),
+        usage(
+            "legacy_gamers",
+            LEGACY_GAMERS_MEMORY_ID,
+            LEGACY_GAMER.with_borrow(|m| m.len()),
+        ),
+        usage(
+            "address_principal_map",
+            ADDRESS_PRINCIPAL_MAP_MEMORY_ID,
+            ADDRESS_PRINCIPLE_MAP.with_borrow(|m| m.len()),
+        ),
+        usage("blocks", BLOCKS_MEMORY_ID, BLOCKS.with_borrow(|m| m.len())),
+        usage("tx_records", TX_RECORDS_MEMORY_ID, TX_RECORDS.with_borrow(|m| m.len())),
+        usage("roles", ROLES_MEMORY_ID, ROLES.with_borrow(|m| m.len())),
+        usage("admin_actions", ADMIN_ACTIONS_MEMORY_ID, ADMIN_ACTIONS.with_borrow(|m| m.len())),
+        usage("legacy_post_game_job", LEGACY_POST_GAME_JOB_MEMORY_ID, 1),
+        usage("metrics", METRICS_MEMORY_ID, 1),
+        usage("events", EVENTS_MEMORY_ID, EVENTS.with_borrow(|m| m.len())),
+        usage("gamer_events", GAMER_EVENTS_MEMORY_ID, GAMER_EVENTS.with_borrow(|m| m.len())),
+        usage("tx_events", TX_EVENTS_MEMORY_ID, TX_EVENTS.with_borrow(|m| m.len())),
+        usage("retention_config", RETENTION_CONFIG_MEMORY_ID, 1),
+        usage("finalized_txs", FINALIZED_TXS_MEMORY_ID, FINALIZED_TXS.with_borrow(|m| m.len())),
+        usage("block_gaps", BLOCK_GAPS_MEMORY_ID, BLOCK_GAPS.with_borrow(|m| m.len())),
+        usage(
+            "rolled_back_txs",
+            ROLLED_BACK_TXS_MEMORY_ID,
+            ROLLED_BACK_TXS.with_borrow(|m| m.len()),
+        ),
+        usage(
+            "rolled_back_heights",
+            ROLLED_BACK_HEIGHTS_MEMORY_ID,
+            ROLLED_BACK_HEIGHTS.with_borrow(|m| m.len()),
+        ),
+        usage("pools", POOLS_MEMORY_ID, POOLS.with_borrow(|m| m.len())),
+        usage("gamers", GAMERS_MEMORY_ID, GAMER.with_borrow(|m| m.len())),
+        usage(
+            "post_game_jobs",
+            POST_GAME_JOBS_MEMORY_ID,
+            POST_GAME_JOBS.with_borrow(|m| m.len()),
+        ),
+    ]
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+
+use ic_canisters_http_types::{HttpResponse, HttpResponseBuilder};
+use ic_metrics_encoder::MetricsEncoder;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+
+use crate::game::gamer::count_gamers;
+use crate::memory::{read_pools, GAMER, METRICS, TX_RECORDS, WASM_PAGE_SIZE_IN_BYTES};
+use crate::state::{GameStatus, Pool, PoolState};
+use crate::*;
+
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+const CLAIM_HOURS_KEPT: u64 = 24;
+
+/// Counters which can't be derived from the state, kept in stable memory to survive upgrades.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MetricCounters {
+    pub total_claims: u64,
+    /// Claims counted by the hour since the epoch, only the last `CLAIM_HOURS_KEPT` are kept.
+    pub hourly_claims: BTreeMap<u64, u64>,
+    pub rollbacks: u64,
+    pub reorgs: u64,
+    pub last_block_height: Option<u32>,
+}
+
+impl Storable for MetricCounters {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+fn mutate_counters(f: impl FnOnce(&mut MetricCounters)) {
+    METRICS.with_borrow_mut(|m| {
+        let mut counters = m.get().clone();
+        f(&mut counters);
+        m.set(counters).expect("Failed to set METRICS");
+    });
+}
+
+fn current_hour() -> u64 {
+    crate::utils::now_nanos() / 1_000_000_000 / SECONDS_PER_HOUR
+}
+
+pub(crate) fn record_claim() {
+    let hour = current_hour();
+    mutate_counters(|c| {
+        c.total_claims += 1;
+        *c.hourly_claims.entry(hour).or_default() += 1;
+        c.hourly_claims.retain(|h, _| *h + CLAIM_HOURS_KEPT > hour);
+    });
+}
+
+pub(crate) fn record_rollback() {
+    mutate_counters(|c| c.rollbacks += 1);
+}
+
+pub(crate) fn record_reorg() {
+    mutate_counters(|c| c.reorgs += 1);
+}
+
+pub(crate) fn record_block(height: u32) {
+    mutate_counters(|c| c.last_block_height = Some(height));
+}
+
+fn game_status_code(status: &GameStatus) -> f64 {
+    match status {
+        GameStatus::Initialize { .. } => 0.0,
+        GameStatus::Play => 1.0,
+        GameStatus::Ended => 2.0,
+        GameStatus::RunesMinted => 3.0,
+        GameStatus::LiquidityAdded => 4.0,
+        GameStatus::Withdrawable => 5.0,
+    }
+}
+
+fn heap_memory_size_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE_IN_BYTES
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+/// The latest pool state without logging an error for the pools not funded yet.
+fn latest_state(pool: &Pool) -> Option<&PoolState> {
+    pool.pending.back().or(pool.confirmed_state.as_ref())
+}
+
+/// Encode a gauge with a value for each of the pools, labeled by the pool address.
+fn encode_pool_gauge(
+    w: &mut MetricsEncoder<Vec<u8>>,
+    name: &str,
+    help: &str,
+    pools: &[Pool],
+    value: impl Fn(&Pool) -> f64,
+) -> std::io::Result<()> {
+    let mut gauge = w.gauge_vec(name, help)?;
+    for pool in pools {
+        gauge = gauge.value(&[("pool", pool.address.as_str())], value(pool))?;
+    }
+    Ok(())
+}
+
+pub fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
+    w.encode_gauge(
+        "ree_cookie_stable_memory_bytes",
+        (ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE_IN_BYTES) as f64,
+        "Size of the stable memory allocated by this canister.",
+    )?;
+    w.encode_gauge(
+        "ree_cookie_heap_memory_bytes",
+        heap_memory_size_bytes() as f64,
+        "Size of the heap memory allocated by this canister.",
+    )?;
+    w.encode_gauge(
+        "ree_cookie_cycle_balance",
+        ic_cdk::api::canister_balance128() as f64,
+        "Cycles balance of this canister.",
+    )?;
+    w.encode_gauge(
+        "ree_cookie_gamers",
+        GAMER.with_borrow(|g| g.len()) as f64,
+        "Number of the registered gamers in all the pools.",
+    )?;
+
+    let counters = METRICS.with_borrow(|m| m.get().clone());
+    w.encode_counter(
+        "ree_cookie_claims_total",
+        counters.total_claims as f64,
+        "Number of the claims since the game started.",
+    )?;
+    w.encode_gauge(
+        "ree_cookie_claims_last_hour",
+        counters
+            .hourly_claims
+            .get(&current_hour().saturating_sub(1))
+            .copied()
+            .unwrap_or_default() as f64,
+        "Number of the claims in the last full hour.",
+    )?;
+    w.encode_counter(
+        "ree_cookie_rollbacks_total",
+        counters.rollbacks as f64,
+        "Number of the txs rolled back by the orchestrator.",
+    )?;
+    w.encode_counter(
+        "ree_cookie_reorgs_total",
+        counters.reorgs as f64,
+        "Number of the recoverable reorgs handled.",
+    )?;
+    w.encode_gauge(
+        "ree_cookie_last_block_height",
+        counters.last_block_height.unwrap_or_default() as f64,
+        "Height of the last block received from the orchestrator.",
+    )?;
+    w.encode_gauge(
+        "ree_cookie_missing_blocks",
+        crate::reorg::get_block_gaps()
+            .iter()
+            .map(|(from, to)| (to - from + 1) as u64)
+            .sum::<u64>() as f64,
+        "Number of the heights skipped by the block feed and not filled yet.",
+    )?;
+    w.encode_gauge(
+        "ree_cookie_tx_records",
+        TX_RECORDS.with_borrow(|t| t.len()) as f64,
+        "Number of the tx records, both unconfirmed and confirmed.",
+    )?;
+    let usage = crate::memory::storage_usage();
+    let mut entries = w.gauge_vec(
+        "ree_cookie_stable_structure_entries",
+        "Number of the entries in each stable structure.",
+    )?;
+    for u in usage.iter() {
+        entries = entries.value(&[("structure", u.name.as_str())], u.entries as f64)?;
+    }
+    let mut bytes = w.gauge_vec(
+        "ree_cookie_stable_structure_bytes",
+        "Size of the stable memory allocated to each stable structure.",
+    )?;
+    for u in usage.iter() {
+        bytes = bytes.value(&[("structure", u.name.as_str())], u.bytes as f64)?;
+    }
+
+    let pools: Vec<Pool> = read_pools(|p| p.values().cloned().collect());
+    w.encode_gauge(
+        "ree_cookie_pools",
+        pools.len() as f64,
+        "Number of the pools hosted by this canister.",
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_pool_gamers",
+        "Number of the gamers registered in the pool.",
+        &pools,
+        |p| count_gamers(&p.address) as f64,
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_game_status",
+        "Phase of the game: 0 Initialize, 1 Play, 2 Ended, 3 RunesMinted, 4 LiquidityAdded, 5 Withdrawable.",
+        &pools,
+        |p| game_status_code(&p.game_status),
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_claimed_cookies",
+        "Number of the cookies claimed by all the gamers of the pool.",
+        &pools,
+        |p| p.game.claimed_cookies as f64,
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_premine_runes",
+        "Amount of the runes premined for the claimed cookies and the liquidity.",
+        &pools,
+        |p| p.premine_rune_amount() as f64,
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_pool_states",
+        "Number of the pool states kept in the pool.",
+        &pools,
+        |p| (p.pending.len() + p.confirmed_state.iter().count()) as f64,
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_unconfirmed_pool_states",
+        "Number of the pool states not finalized yet.",
+        &pools,
+        |p| p.pending.len() as f64,
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_pool_btc_balance",
+        "BTC balance of the pool in sats.",
+        &pools,
+        |p| latest_state(p).map(|s| s.btc_balance()).unwrap_or_default() as f64,
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_pool_rune_balance",
+        "Rune balance of the pool.",
+        &pools,
+        |p| latest_state(p).map(|s| s.rune_balance).unwrap_or_default() as f64,
+    )?;
+    encode_pool_gauge(
+        w,
+        "ree_cookie_pool_nonce",
+        "Nonce of the latest pool state.",
+        &pools,
+        |p| latest_state(p).map(|s| s.nonce).unwrap_or_default() as f64,
+    )?;
+    Ok(())
+}
+
+pub fn serve_metrics() -> HttpResponse {
+    let mut writer = MetricsEncoder::new(vec![], crate::utils::now_nanos() as i64 / 1_000_000);
+    match encode_metrics(&mut writer) {
+        Ok(()) => HttpResponseBuilder::ok()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .with_body_and_content_length(writer.into_inner())
+            .build(),
+        Err(err) => HttpResponseBuilder::server_error(format!("Failed to encode metrics: {}", err))
+            .build(),
+    }
+}
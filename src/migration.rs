@@ -0,0 +1,509 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+
+use ree_types::CoinId;
+use serde::de::DeserializeOwned;
+
+use crate::game::game::Game;
+use crate::game::gamer::{insert_gamer, Gamer};
+use crate::memory::{
+    insert_pool, read_pools, LEGACY_GAMER, LEGACY_POST_GAME_JOB, POST_GAME_JOBS, ROLES, STATE,
+};
+use crate::event::{Event, EventIndex};
+use crate::metrics::MetricCounters;
+use crate::post_game::PostGameJob;
+use crate::retention::RetentionConfig;
+use crate::role::{AdminAction, Roles};
+use crate::network::BtcNetwork;
+use crate::state::{ExchangeState, GameStatus, Pool, PoolState, StoredState, UserAction};
+use crate::*;
+
+/// Every value we store is prefixed with `ENVELOPE_MAGIC` and its layout version.
+/// Values written before the envelope was introduced have no prefix and are version 0,
+/// a bincode payload can't start with the magic since it would be a length over 4M.
+const ENVELOPE_MAGIC: [u8; 3] = [0xff, b'R', b'C'];
+
+/// A value stored with bincode in a versioned envelope.
+///
+/// Bump `VERSION` whenever the bincode layout changes (including the layout of any nested
+/// type), keep the old layout as a `*V{N}` struct, and decode it in `migrate` through
+/// explicit `migrate_v{N}_to_v{N+1}` functions. The old layouts nest their own copies of our
+/// types, so that changing a live type can't change how the old payloads decode.
+pub(crate) trait Versioned: Serialize + DeserializeOwned {
+    const VERSION: u8;
+
+    /// Decode a payload written with an older `version` into the current layout.
+    fn migrate(version: u8, payload: &[u8]) -> Self;
+}
+
+pub(crate) fn encode<T: Versioned>(value: &T) -> Cow<'static, [u8]> {
+    let mut bytes = ENVELOPE_MAGIC.to_vec();
+    bytes.push(T::VERSION);
+    bytes.extend(bincode::serialize(value).unwrap());
+    Cow::Owned(bytes)
+}
+
+fn open_envelope(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes.strip_prefix(&ENVELOPE_MAGIC) {
+        Some([version, payload @ ..]) => (*version, payload),
+        _ => (0, bytes),
+    }
+}
+
+pub(crate) fn decode<T: Versioned>(bytes: &[u8]) -> T {
+    let (version, payload) = open_envelope(bytes);
+    if version == T::VERSION {
+        bincode::deserialize(payload).unwrap()
+    } else {
+        T::migrate(version, payload)
+    }
+}
+
+fn unknown_version<T>(version: u8) -> T {
+    panic!(
+        "unknown version {} of {}",
+        version,
+        std::any::type_name::<T>()
+    )
+}
+
+/// The layout of `Game` nested in the old layouts of `ExchangeState`.
+#[derive(Deserialize)]
+pub(crate) struct GameV0 {
+    pub is_end: bool,
+    pub gamer_register_fee: u64,
+    pub claim_cooling_down: u64,
+    pub cookie_amount_per_claim: u128,
+    pub claimed_cookies: u128,
+    pub already_add_liquidity: bool,
+    pub start_time: u64,
+}
+
+impl From<GameV0> for Game {
+    fn from(old: GameV0) -> Self {
+        Self {
+            is_end: old.is_end,
+            gamer_register_fee: old.gamer_register_fee,
+            claim_cooling_down: old.claim_cooling_down,
+            cookie_amount_per_claim: old.cookie_amount_per_claim,
+            claimed_cookies: old.claimed_cookies,
+            already_add_liquidity: old.already_add_liquidity,
+            start_time: old.start_time,
+        }
+    }
+}
+
+/// The layout of `GameStatus` nested in the old layouts of `ExchangeState`.
+#[derive(Deserialize)]
+pub(crate) enum GameStatusV0 {
+    Initialize { init_key: bool, init_btc: bool },
+    Play,
+    Ended,
+    RunesMinted,
+    LiquidityAdded,
+    Withdrawable,
+}
+
+impl From<GameStatusV0> for GameStatus {
+    fn from(old: GameStatusV0) -> Self {
+        match old {
+            GameStatusV0::Initialize { init_key, init_btc } => {
+                GameStatus::Initialize { init_key, init_btc }
+            }
+            GameStatusV0::Play => GameStatus::Play,
+            GameStatusV0::Ended => GameStatus::Ended,
+            GameStatusV0::RunesMinted => GameStatus::RunesMinted,
+            GameStatusV0::LiquidityAdded => GameStatus::LiquidityAdded,
+            GameStatusV0::Withdrawable => GameStatus::Withdrawable,
+        }
+    }
+}
+
+/// The layout of `UserAction` nested in the old layouts of `ExchangeState`, `AddLiquidity`
+/// was appended so the payloads written before it still decode.
+#[derive(Deserialize)]
+pub(crate) enum UserActionV0 {
+    Init,
+    Register(Address),
+    Withdraw(Address),
+    AddLiquidity,
+}
+
+impl From<UserActionV0> for UserAction {
+    fn from(old: UserActionV0) -> Self {
+        match old {
+            UserActionV0::Init => UserAction::Init,
+            UserActionV0::Register(address) => UserAction::Register(address),
+            UserActionV0::Withdraw(address) => UserAction::Withdraw(address),
+            UserActionV0::AddLiquidity => UserAction::AddLiquidity,
+        }
+    }
+}
+
+/// The layout of `PoolState` nested in the old layouts of `ExchangeState`.
+#[derive(Deserialize)]
+pub(crate) struct PoolStateV0 {
+    pub id: Option<Txid>,
+    pub nonce: u64,
+    pub utxo: Utxo,
+    pub rune_utxo: Option<Utxo>,
+    pub rune_balance: u128,
+    pub user_action: UserActionV0,
+}
+
+impl From<PoolStateV0> for PoolState {
+    fn from(old: PoolStateV0) -> Self {
+        Self {
+            id: old.id,
+            nonce: old.nonce,
+            utxo: old.utxo,
+            rune_utxo: old.rune_utxo,
+            rune_balance: old.rune_balance,
+            user_action: old.user_action.into(),
+        }
+    }
+}
+
+/// The layout of `ExchangeState` before the network and the pool key path were stored.
+#[derive(Deserialize)]
+pub(crate) struct ExchangeStateV0 {
+    pub rune_name: String,
+    pub rune_id: Option<CoinId>,
+    pub key: Option<Pubkey>,
+    pub address: Option<String>,
+    pub game: GameV0,
+    pub orchestrator: Principal,
+    pub states: Vec<PoolStateV0>,
+    pub ii_canister: Principal,
+    pub btc_customs_principle: Principal,
+    pub etching_key: Option<String>,
+    pub richswap_pool_address: String,
+    pub game_status: GameStatusV0,
+}
+
+/// The v0 canister only ran on testnet4 and always derived the pool key with `key_1` from
+/// the bytes of the rune name, and `states[0]` was its finalized state. A pool without a key
+/// never got an address, so no gamer could have joined it and there is nothing to key it by,
+/// it has to be created again with `create_pool`.
+fn migrate_exchange_state_v0_to_v1(old: ExchangeStateV0) -> StoredState {
+    let legacy_pool = match (old.key, old.address) {
+        (Some(key), Some(address)) => {
+            let mut states: VecDeque<PoolState> = old.states.into_iter().map(Into::into).collect();
+            Some(Pool {
+                key_derivation_path: old.rune_name.clone().into_bytes(),
+                rune_name: old.rune_name,
+                rune_id: old.rune_id,
+                key,
+                address,
+                game: old.game.into(),
+                confirmed_state: states.pop_front(),
+                pending: states,
+                etching_key: old.etching_key,
+                richswap_pool_address: old.richswap_pool_address,
+                game_status: old.game_status.into(),
+            })
+        }
+        _ => {
+            log!(
+                WARNING,
+                "dropped the pool {} which has no key or address",
+                old.rune_name
+            );
+            None
+        }
+    };
+    StoredState {
+        state: ExchangeState {
+            key_id: "key_1".to_string(),
+            orchestrator: old.orchestrator,
+            ii_canister: old.ii_canister,
+            btc_customs_principle: old.btc_customs_principle,
+            network: BtcNetwork::Testnet4,
+        },
+        legacy_pool,
+    }
+}
+
+/// Decode the value of `STATE`, the v0 layout also holds the pool hosted by the exchange so
+/// it is split here rather than in `ExchangeState::migrate`.
+pub(crate) fn decode_stored_state(bytes: &[u8]) -> StoredState {
+    match open_envelope(bytes) {
+        (0, payload) => migrate_exchange_state_v0_to_v1(bincode::deserialize(payload).unwrap()),
+        _ => StoredState {
+            state: decode(bytes),
+            legacy_pool: None,
+        },
+    }
+}
+
+impl Versioned for ExchangeState {
+    const VERSION: u8 = 1;
+
+    /// The v0 layout is decoded by `decode_stored_state`.
+    fn migrate(version: u8, _payload: &[u8]) -> Self {
+        unknown_version::<Self>(version)
+    }
+}
+
+/// The types below kept their layout when the envelope was introduced.
+macro_rules! versioned_since_v0 {
+    ($($t:ty),*) => {
+        $(
+            impl Versioned for $t {
+                const VERSION: u8 = 1;
+
+                fn migrate(version: u8, payload: &[u8]) -> Self {
+                    match version {
+                        0 => bincode::deserialize(payload).unwrap(),
+                        _ => unknown_version::<Self>(version),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+versioned_since_v0!(Gamer, Roles, AdminAction, PostGameJob, EventIndex);
+
+/// The types below were introduced with the envelope and have no older layout.
+macro_rules! versioned_since_v1 {
+    ($($t:ty),*) => {
+        $(
+            impl Versioned for $t {
+                const VERSION: u8 = 1;
+
+                fn migrate(version: u8, _payload: &[u8]) -> Self {
+                    unknown_version::<Self>(version)
+                }
+            }
+        )*
+    };
+}
+
+versioned_since_v1!(MetricCounters, RetentionConfig, Pool, Event);
+
+/// Rewrite the stored values in the current layout, called in `post_upgrade` after
+/// `load_state` so that the migrations of a version only have to run once. The pool of a
+/// state written before the pools were keyed by address is moved into the pools along with
+/// its gamers and post game job, `post_upgrade` then calls `flush_state` so that `STATE` no
+/// longer holds it.
+pub(crate) fn migrate_stable_memory() {
+    ROLES.with_borrow_mut(|r| {
+        let roles: Vec<_> = r.iter().collect();
+        for (principal, roles) in roles {
+            r.insert(principal, roles);
+        }
+    });
+    crate::retention::backfill_rolled_back_heights();
+    let legacy_pool = STATE.with_borrow(|c| c.get().as_ref().and_then(|s| s.legacy_pool.clone()));
+    if let Some(pool) = legacy_pool {
+        let address = pool.address.clone();
+        if read_pools(|p| p.contains_key(&address)) {
+            // never overwrite the live pool with the one left in an older layout
+            log!(WARNING, "the pool {} was already moved into the pools", address);
+        } else {
+            insert_pool(pool);
+            let gamers: Vec<Gamer> =
+                LEGACY_GAMER.with_borrow(|g| g.iter().map(|(_, g)| g).collect());
+            for gamer in gamers.iter() {
+                insert_gamer(&address, gamer.clone());
+            }
+            let job = LEGACY_POST_GAME_JOB.with_borrow(|j| j.get().clone());
+            POST_GAME_JOBS.with_borrow_mut(|j| j.insert(address.clone(), job));
+            log!(
+                INFO,
+                "moved the pool {} with {} gamers into the pools",
+                address,
+                gamers.len()
+            );
+        }
+        LEGACY_GAMER.with_borrow_mut(|g| g.clear_new());
+        LEGACY_POST_GAME_JOB.with_borrow_mut(|j| {
+            j.set(PostGameJob::default())
+                .expect("Failed to clear LEGACY_POST_GAME_JOB.")
+        });
+    }
+    log!(INFO, "stable memory migrated to the current layout");
+}
+
+#[test]
+pub fn test_decode_exchange_state_v0_fixture() {
+    // ExchangeState written by the canister before the envelope: rune_name `COOKIE`,
+    // address `bc1pcookie`, 2400 claimed cookies, no pool states and status `Play`
+    let fixture = hex::decode(
+        "0600000000000000434f4f4b49450000010a0000000000000062633170636f6f6b69650010270000\
+         000000003c0000000000000064000000000000000000000000000000600900000000000000000000\
+         00000000000000000000000000010000000000000004000000000000000001000000000000000401\
+         0000000000000004000c0000000000000062633170726963687377617001000000",
+    )
+    .unwrap();
+    let state: ExchangeStateV0 = bincode::deserialize(&fixture).unwrap();
+    assert_eq!(state.rune_name, "COOKIE");
+    assert_eq!(state.address, Some("bc1pcookie".to_string()));
+    assert_eq!(state.game.gamer_register_fee, 10000);
+    assert_eq!(state.game.claim_cooling_down, 60);
+    assert_eq!(state.game.cookie_amount_per_claim, 100);
+    assert_eq!(state.game.claimed_cookies, 2400);
+    assert!(state.states.is_empty());
+    assert_eq!(state.richswap_pool_address, "bc1prichswap");
+    assert!(matches!(state.game_status, GameStatusV0::Play));
+
+    let stored = decode_stored_state(&fixture);
+    assert_eq!(stored.state.orchestrator, Principal::anonymous());
+    assert_eq!(stored.state.network, BtcNetwork::Testnet4);
+    assert_eq!(stored.state.key_id, "key_1");
+    // the fixture has no pool key, so there is no pool to move
+    assert!(stored.legacy_pool.is_none());
+
+    let bytes = encode(&stored.state);
+    assert_eq!(bytes[..4], [0xff, b'R', b'C', ExchangeState::VERSION]);
+    let state: ExchangeState = decode(&bytes);
+    assert_eq!(state.key_id, "key_1");
+}
+
+/// `ExchangeState` v0 of the pool `bc1pcookie` of rune `COOKIE` in `Play`, with an init
+/// state for each of `utxos`. Our layout is pinned as bytes, the ree-types values are
+/// encoded by their own serde impls since their layout isn't ours to freeze.
+#[cfg(test)]
+fn exchange_state_v0_fixture(utxos: &[Utxo]) -> Vec<u8> {
+    // rune_name `COOKIE`, no rune_id, some key
+    let mut bytes = hex::decode("0600000000000000434f4f4b49450001").unwrap();
+    bytes.extend(bincode::serialize(&crate::fixtures::mock_pubkey()).unwrap());
+    bytes.extend(
+        hex::decode(concat!(
+            // address `bc1pcookie`
+            "010a0000000000000062633170636f6f6b6965",
+            // game: register fee 10000, cooling down 60s, 100 cookies per claim
+            "00",
+            "1027000000000000",
+            "3c00000000000000",
+            "64000000000000000000000000000000",
+            "00000000000000000000000000000000",
+            "00",
+            "0000000000000000",
+            // anonymous orchestrator
+            "010000000000000004",
+        ))
+        .unwrap(),
+    );
+    bytes.extend((utxos.len() as u64).to_le_bytes());
+    for (nonce, utxo) in utxos.iter().enumerate() {
+        // no txid, the nonce and the utxo, no rune utxo, no runes, `Init`
+        bytes.push(0);
+        bytes.extend((nonce as u64).to_le_bytes());
+        bytes.extend(bincode::serialize(utxo).unwrap());
+        bytes.extend(hex::decode("000000000000000000000000000000000000000000").unwrap());
+    }
+    bytes.extend(
+        hex::decode(concat!(
+            // anonymous ii and customs, no etching key, no richswap pool, `Play`
+            "010000000000000004",
+            "010000000000000004",
+            "00",
+            "0000000000000000",
+            "01000000",
+        ))
+        .unwrap(),
+    );
+    bytes
+}
+
+#[test]
+pub fn test_decode_exchange_state_v0_with_pool() {
+    let utxos: Vec<Utxo> = (0..3)
+        .map(|vout| {
+            Utxo::try_from(format!("{:064x}:{}", 1, vout), None, 10000 * (vout as u64 + 1))
+                .unwrap()
+        })
+        .collect();
+
+    let stored = decode_stored_state(&exchange_state_v0_fixture(&utxos));
+    assert_eq!(stored.state.key_id, "key_1");
+    assert_eq!(stored.state.network, BtcNetwork::Testnet4);
+    let pool = stored.legacy_pool.unwrap();
+    assert_eq!(pool.address, "bc1pcookie");
+    assert_eq!(pool.key, crate::fixtures::mock_pubkey());
+    assert_eq!(pool.key_derivation_path, b"COOKIE".to_vec());
+    assert_eq!(pool.game.gamer_register_fee, 10000);
+    assert!(matches!(pool.game_status, GameStatus::Play));
+    assert_eq!(pool.confirmed_state.map(|s| s.nonce), Some(0));
+    assert_eq!(
+        pool.pending.iter().map(|s| s.utxo.sats).collect::<Vec<_>>(),
+        vec![20000, 30000]
+    );
+}
+
+#[test]
+pub fn test_migrate_single_pool_into_pools() {
+    use crate::game::gamer::{count_gamers, get_gamer};
+    use crate::memory::{
+        clear_pools, flush_state, get_pool, load_state, mutate_pool, pool_addresses, GAMER,
+    };
+    use crate::post_game::{get_post_game_job, PostGameStep};
+
+    let set_stored_state = |bytes: Vec<u8>| {
+        STATE.with_borrow_mut(|c| c.set(Some(decode_stored_state(&bytes))).unwrap());
+    };
+
+    clear_pools();
+    GAMER.with_borrow_mut(|m| m.clear_new());
+    LEGACY_GAMER.with_borrow_mut(|g| {
+        for address in ["a", "b"] {
+            g.insert(address.to_string(), Gamer::new(address.to_string()));
+        }
+    });
+    LEGACY_POST_GAME_JOB.with_borrow_mut(|j| {
+        j.set(PostGameJob {
+            step: PostGameStep::EtchRune,
+            attempts: 2,
+            ..Default::default()
+        })
+        .unwrap();
+    });
+
+    set_stored_state(exchange_state_v0_fixture(&[]));
+    load_state();
+    migrate_stable_memory();
+    flush_state();
+    assert_eq!(get_pool("bc1pcookie").unwrap().rune_name, "COOKIE");
+    assert_eq!(count_gamers("bc1pcookie"), 2);
+    assert!(get_gamer("bc1pcookie", "a").is_some());
+    assert!(LEGACY_GAMER.with_borrow(|g| g.is_empty()));
+    assert_eq!(get_post_game_job("bc1pcookie").attempts, 2);
+    assert_eq!(LEGACY_POST_GAME_JOB.with_borrow(|j| j.get().attempts), 0);
+    // the flush dropped the older layout from stable memory
+    assert!(STATE.with_borrow(|c| c.get().as_ref().unwrap().legacy_pool.is_none()));
+
+    // migrating again leaves the pools alone
+    load_state();
+    migrate_stable_memory();
+    assert_eq!(pool_addresses().len(), 1);
+    assert_eq!(count_gamers("bc1pcookie"), 2);
+
+    // an older layout left in `STATE` can't overwrite the live pool
+    mutate_pool("bc1pcookie", |p| {
+        p.game.claimed_cookies = 500;
+        Ok(())
+    })
+    .unwrap();
+    set_stored_state(exchange_state_v0_fixture(&[]));
+    migrate_stable_memory();
+    assert_eq!(get_pool("bc1pcookie").unwrap().game.claimed_cookies, 500);
+}
+
+#[test]
+pub fn test_decode_gamer_v0_fixture() {
+    // Gamer `bc1pgamer` with 300 cookies, last claimed at 1700000000
+    let fixture = hex::decode(
+        "09000000000000006263317067616d65722c01000000000000000000000000000000f153650000000000",
+    )
+    .unwrap();
+    let gamer: Gamer = decode(&fixture);
+    assert_eq!(gamer.address, "bc1pgamer");
+    assert_eq!(gamer.cookies, 300);
+    assert_eq!(gamer.last_click_time, 1700000000);
+    assert!(!gamer.is_withdrawn);
+
+    let gamer: Gamer = decode(&encode(&gamer));
+    assert_eq!(gamer.cookies, 300);
+}
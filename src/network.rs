@@ -0,0 +1,69 @@
+use ic_cdk::api::management_canister::bitcoin::BitcoinNetwork;
+use ree_types::bitcoin::Network;
+
+use crate::*;
+
+/// The bitcoin network the exchange runs on, chosen in the init args.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BtcNetwork {
+    Mainnet,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+impl BtcNetwork {
+    pub fn bitcoin_network(&self) -> Network {
+        match self {
+            BtcNetwork::Mainnet => Network::Bitcoin,
+            BtcNetwork::Testnet4 => Network::Testnet4,
+            BtcNetwork::Signet => Network::Signet,
+            BtcNetwork::Regtest => Network::Regtest,
+        }
+    }
+
+    /// The network of the IC bitcoin canister, which doesn't serve signet.
+    pub fn management_network(&self) -> Result<BitcoinNetwork> {
+        match self {
+            BtcNetwork::Mainnet => Ok(BitcoinNetwork::Mainnet),
+            BtcNetwork::Testnet4 => Ok(BitcoinNetwork::Testnet),
+            BtcNetwork::Regtest => Ok(BitcoinNetwork::Regtest),
+            BtcNetwork::Signet => Err(ExchangeError::InvalidState(
+                "the bitcoin canister doesn't support signet".to_string(),
+            )),
+        }
+    }
+
+    pub fn schnorr_key_name(&self) -> &'static str {
+        match self {
+            BtcNetwork::Mainnet => "key_1",
+            BtcNetwork::Testnet4 | BtcNetwork::Signet => "test_key_1",
+            BtcNetwork::Regtest => "dfx_test_key",
+        }
+    }
+
+    pub fn rune_indexer(&self) -> Principal {
+        match self {
+            BtcNetwork::Mainnet => Principal::from_text(RUNE_INDEXER_CANISTER).unwrap(),
+            _ => Principal::from_text(TESTNET_RUNE_INDEXER_CANISTER).unwrap(),
+        }
+    }
+}
+
+#[test]
+pub fn test_btc_network_settings() {
+    assert_eq!(BtcNetwork::Mainnet.bitcoin_network(), Network::Bitcoin);
+    assert_eq!(BtcNetwork::Testnet4.bitcoin_network(), Network::Testnet4);
+    assert_eq!(BtcNetwork::Mainnet.schnorr_key_name(), "key_1");
+    assert_eq!(BtcNetwork::Testnet4.schnorr_key_name(), "test_key_1");
+    assert_eq!(BtcNetwork::Regtest.schnorr_key_name(), "dfx_test_key");
+    assert_eq!(
+        BtcNetwork::Mainnet.rune_indexer(),
+        Principal::from_text(RUNE_INDEXER_CANISTER).unwrap()
+    );
+    assert_eq!(
+        BtcNetwork::Testnet4.rune_indexer(),
+        Principal::from_text(TESTNET_RUNE_INDEXER_CANISTER).unwrap()
+    );
+    assert!(BtcNetwork::Signet.management_network().is_err());
+}
@@ -0,0 +1,331 @@
+use std::time::Duration;
+
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+
+use crate::event::{record_pool_event, EventKind};
+use crate::external::bitcoin_customs::{etching_v3, EtchingArgs};
+use crate::memory::{mutate_pool, pool_addresses, read_pool, POST_GAME_JOBS};
+use crate::state::{GameStatus, Pool};
+use crate::utils::{
+    fetch_confirmed_utxos, fetch_etched_rune_id, fetch_premine_rune_balance, fetch_rune_balances,
+};
+use crate::*;
+
+const POST_GAME_JOB_INTERVAL: Duration = Duration::from_secs(60);
+const RETRY_BACKOFF_BASE_SECS: u64 = 60;
+const RETRY_BACKOFF_MAX_SECS: u64 = 60 * 60;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PostGameStep {
+    #[default]
+    WaitGameEnd,
+    EtchRune,
+    ConfirmEtching,
+    /// The add_liquidity tx needs an initiator to fund it and goes through the orchestrator
+    /// like any intention, so building it is out of scope of the job, which only waits for
+    /// `execute_tx` to move the game into `LiquidityAdded`.
+    AwaitAddLiquidity,
+    Done,
+}
+
+/// Progress of the post game job, persisted so that it resumes after an upgrade.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PostGameJob {
+    pub step: PostGameStep,
+    pub attempts: u32,
+    pub next_run_at: u64,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+impl Storable for PostGameJob {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static JOB_RUNNING: std::cell::Cell<bool> = std::cell::Cell::new(false);
+    static ETCHING_POOLS: std::cell::RefCell<std::collections::BTreeSet<PoolAddress>> =
+        std::cell::RefCell::new(std::collections::BTreeSet::new());
+}
+
+/// Holds `JOB_RUNNING` while the job runs. A trap in a callback makes the cdk drop the future
+/// of the job, so the flag is released even when the job never gets to its end.
+struct JobGuard;
+
+impl JobGuard {
+    fn new() -> Option<Self> {
+        (!JOB_RUNNING.with(|r| r.replace(true))).then_some(JobGuard)
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        JOB_RUNNING.with(|r| r.set(false));
+    }
+}
+
+/// Holds the pool in `ETCHING_POOLS` while its etching call is in flight, the `etch_rune`
+/// endpoint and the job could otherwise both etch the rune and pay for it twice.
+struct EtchGuard(PoolAddress);
+
+impl EtchGuard {
+    fn new(pool: &str) -> Option<Self> {
+        ETCHING_POOLS
+            .with_borrow_mut(|p| p.insert(pool.to_string()))
+            .then(|| EtchGuard(pool.to_string()))
+    }
+}
+
+impl Drop for EtchGuard {
+    fn drop(&mut self) {
+        ETCHING_POOLS.with_borrow_mut(|p| p.remove(&self.0));
+    }
+}
+
+pub fn get_post_game_job(pool: &str) -> PostGameJob {
+    POST_GAME_JOBS
+        .with_borrow(|j| j.get(&pool.to_string()))
+        .unwrap_or_default()
+}
+
+fn set_post_game_job(pool: &str, job: PostGameJob) {
+    POST_GAME_JOBS.with_borrow_mut(|j| j.insert(pool.to_string(), job));
+}
+
+/// Etch the rune of `pool` with the premine paid to the pool address.
+pub(crate) async fn etch(pool: &str) -> Result<String> {
+    let _guard = EtchGuard::new(pool).ok_or(ExchangeError::InvalidState(
+        "rune etching in progress".to_string(),
+    ))?;
+    let (etching_args, address) = read_pool(pool, |p| -> Result<_> {
+        matches!(p.game_status, GameStatus::Ended)
+            .then(|| ())
+            .ok_or(ExchangeError::GameNotEnd)?;
+        p.etching_key
+            .is_none()
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState("rune already etched".to_string()))?;
+        Ok((
+            EtchingArgs {
+                rune_name: p.rune_name.clone(),
+                divisibility: Some(8),
+                premine: Some(p.premine_rune_amount()),
+                logo: None,
+                symbol: None,
+                terms: None,
+                turbo: false,
+            },
+            p.address.clone(),
+        ))
+    })??;
+
+    let etch_key = etching_v3(etching_args, address)
+        .await
+        .map_err(|(code, msg)| ExchangeError::EtchingError(code, msg))?
+        .0
+        .map_err(ExchangeError::BitcoinCustomsResultError)?;
+    mutate_pool(pool, |p| {
+        // checked again since the state may have changed while awaiting the etching
+        p.etching_key
+            .is_none()
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState("rune already etched".to_string()))?;
+        p.etching_key = Some(etch_key.clone());
+        Ok(())
+    })?;
+    record_pool_event(
+        pool,
+        EventKind::RuneEtched {
+            etching_key: etch_key.clone(),
+        },
+    );
+
+    Ok(etch_key)
+}
+
+/// Verify `premine_rune_utxo` on chain and record it as the rune utxo of `pool`. Only its
+/// outpoint is taken from the caller, the sats come from the confirmed utxos of the pool.
+pub(crate) async fn update_rune_info(pool: &str, premine_rune_utxo: Utxo) -> Result<()> {
+    let (etching_key, premine) = read_pool(pool, |p| -> Result<_> {
+        Ok((
+            p.etching_key.clone().ok_or(ExchangeError::InvalidState(
+                "rune not etched yet".to_string(),
+            ))?,
+            p.premine_rune_amount(),
+        ))
+    })??;
+    let premine_outpoint = premine_rune_utxo.outpoint();
+    let rune_balance = fetch_premine_rune_balance(etching_key, premine_outpoint.clone()).await?;
+    (rune_balance.value == premine)
+        .then(|| ())
+        .ok_or(ExchangeError::DepositRuneBalanceIncorrect(
+            premine.to_string(),
+            rune_balance.value.to_string(),
+        ))?;
+    let sats = fetch_confirmed_utxos(pool, MIN_ETCHING_CONFIRMATIONS)
+        .await?
+        .into_iter()
+        .find(|(outpoint, _)| outpoint.eq(&premine_outpoint))
+        .map(|(_, sats)| sats)
+        .ok_or(ExchangeError::PoolOutputNotFound(format!(
+            "{} is not a confirmed utxo of {}",
+            premine_outpoint, pool
+        )))?;
+    let rune_utxo = Utxo::try_from(premine_outpoint.clone(), Some(rune_balance.clone()), sats)
+        .map_err(|e| ExchangeError::CustomError(e.to_string()))?;
+
+    mutate_pool(pool, |p| {
+        p.game_status.rune_minted()?;
+        let last_state = p.last_state_mut()?;
+        last_state.rune_balance = rune_balance.value;
+        last_state.rune_utxo = Some(rune_utxo);
+        p.rune_id = Some(rune_balance.id);
+        Ok(())
+    })?;
+    record_pool_event(
+        pool,
+        EventKind::RunesMinted {
+            rune_id: rune_balance.id,
+            premine_utxo: premine_outpoint,
+        },
+    );
+    Ok(())
+}
+
+/// Find the utxo of the address of `pool` which holds the whole premine.
+async fn find_premine_rune_utxo(pool: &str, etching_key: String) -> Result<Utxo> {
+    let rune_id = fetch_etched_rune_id(etching_key).await?;
+    let (pool_utxo, premine) = read_pool(pool, |p| -> Result<_> {
+        Ok((p.last_state()?.utxo, p.premine_rune_amount()))
+    })??;
+    let candidates: Vec<(String, u64)> = fetch_confirmed_utxos(pool, MIN_ETCHING_CONFIRMATIONS)
+        .await?
+        .into_iter()
+        .filter(|(outpoint, _)| outpoint.ne(&pool_utxo.outpoint()))
+        .collect();
+    let balances =
+        fetch_rune_balances(candidates.iter().map(|(o, _)| o.clone()).collect(), rune_id).await?;
+    let (outpoint, sats) = candidates
+        .into_iter()
+        .zip(balances.into_iter())
+        .find(|(_, balance)| *balance == premine)
+        .map(|(candidate, _)| candidate)
+        .ok_or(ExchangeError::RuneIndexerResultError(format!(
+            "no utxo of the pool holds the premine {} of {}",
+            premine, rune_id
+        )))?;
+
+    Utxo::try_from(outpoint, None, sats).map_err(|e| ExchangeError::CustomError(e.to_string()))
+}
+
+async fn run_step(pool: &str, step: &PostGameStep) -> Result<()> {
+    match step {
+        PostGameStep::EtchRune => {
+            let etching_key = etch(pool).await?;
+            log!(INFO, "post game job of {} etched rune: {}", pool, etching_key);
+        }
+        PostGameStep::ConfirmEtching => {
+            let etching_key = read_pool(pool, |p| p.etching_key.clone())?.ok_or(
+                ExchangeError::InvalidState("rune not etched yet".to_string()),
+            )?;
+            let premine_rune_utxo = find_premine_rune_utxo(pool, etching_key).await?;
+            update_rune_info(pool, premine_rune_utxo.clone()).await?;
+            log!(
+                INFO,
+                "post game job of {} updated rune info with: {}",
+                pool,
+                premine_rune_utxo.outpoint()
+            );
+        }
+        PostGameStep::WaitGameEnd | PostGameStep::AwaitAddLiquidity | PostGameStep::Done => {}
+    }
+    Ok(())
+}
+
+fn current_step(pool: &Pool) -> PostGameStep {
+    match pool.game_status {
+        GameStatus::Initialize { .. } | GameStatus::Play => PostGameStep::WaitGameEnd,
+        GameStatus::Ended if pool.etching_key.is_none() => PostGameStep::EtchRune,
+        GameStatus::Ended => PostGameStep::ConfirmEtching,
+        GameStatus::RunesMinted => PostGameStep::AwaitAddLiquidity,
+        GameStatus::LiquidityAdded | GameStatus::Withdrawable => PostGameStep::Done,
+    }
+}
+
+async fn run_pool_job(pool: &str) -> Result<()> {
+    let now = crate::utils::now_nanos();
+    let mut job = get_post_game_job(pool);
+    let step = read_pool(pool, current_step)?;
+    if step != job.step {
+        log!(
+            INFO,
+            "post game job of {} moves from {:?} to {:?}",
+            pool,
+            job.step,
+            step
+        );
+        job = PostGameJob {
+            step: step.clone(),
+            updated_at: now,
+            ..Default::default()
+        };
+        set_post_game_job(pool, job.clone());
+    }
+
+    if now >= job.next_run_at {
+        if let Err(e) = run_step(pool, &step).await {
+            let backoff = RETRY_BACKOFF_BASE_SECS
+                .saturating_mul(1u64 << job.attempts.min(16))
+                .min(RETRY_BACKOFF_MAX_SECS);
+            log!(
+                WARNING,
+                "post game job of {} step {:?} failed: {}, retry in {}s",
+                pool,
+                step,
+                e,
+                backoff
+            );
+            let now = crate::utils::now_nanos();
+            set_post_game_job(
+                pool,
+                PostGameJob {
+                    step,
+                    attempts: job.attempts.saturating_add(1),
+                    next_run_at: now + backoff * 1_000_000_000,
+                    last_error: Some(e.to_string()),
+                    updated_at: now,
+                },
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Run the job of every pool in turn, a failing pool backs off on its own.
+async fn run_post_game_job() {
+    let Some(_guard) = JobGuard::new() else {
+        return;
+    };
+    for pool in pool_addresses() {
+        if let Err(e) = run_pool_job(&pool).await {
+            log!(WARNING, "post game job of {} skipped: {}", pool, e);
+        }
+    }
+}
+
+/// Drive the games through `Ended -> RunesMinted` in the background, and wait for
+/// the add_liquidity intention afterwards, see `PostGameStep::AwaitAddLiquidity`.
+pub(crate) fn start_post_game_timer() {
+    ic_cdk_timers::set_timer_interval(POST_GAME_JOB_INTERVAL, || {
+        ic_cdk::spawn(run_post_game_job())
+    });
+}
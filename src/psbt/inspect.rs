@@ -0,0 +1,666 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use ree_types::bitcoin::{
+    self,
+    opcodes::all::{OP_PUSHNUM_13, OP_RETURN},
+    script::Instruction,
+    Network, Psbt, ScriptBuf, Transaction,
+};
+use ree_types::{CoinId, OutputCoin};
+
+use crate::state::PoolState;
+use crate::*;
+
+const TAG_BODY: u128 = 0;
+const TAG_POINTER: u128 = 22;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edict {
+    pub id: CoinId,
+    pub amount: u128,
+    pub output: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Runestone {
+    pub edicts: Vec<Edict>,
+    pub pointer: Option<u32>,
+}
+
+pub(crate) fn address_to_script(address: &str, network: Network) -> Result<ScriptBuf> {
+    bitcoin::Address::from_str(address)
+        .map_err(|e| ExchangeError::InvalidAddress(format!("{}: {}", address, e)))?
+        .require_network(network)
+        .map_err(|e| ExchangeError::InvalidAddress(format!("{}: {}", address, e)))
+        .map(|address| address.script_pubkey())
+}
+
+fn is_op_return(script: &ScriptBuf) -> bool {
+    script.as_bytes().first() == Some(&OP_RETURN.to_u8())
+}
+
+fn decode_varint(buf: &[u8]) -> Result<(u128, usize)> {
+    let mut n = 0u128;
+    for (i, byte) in buf.iter().enumerate() {
+        if i > 18 {
+            return Err(ExchangeError::InvalidRunestone("varint overlong".to_string()));
+        }
+        let value = u128::from(byte & 0b0111_1111);
+        if i == 18 && value > 0b11 {
+            return Err(ExchangeError::InvalidRunestone("varint overflow".to_string()));
+        }
+        n |= value << (7 * i);
+        if byte & 0b1000_0000 == 0 {
+            return Ok((n, i + 1));
+        }
+    }
+    Err(ExchangeError::InvalidRunestone("varint unterminated".to_string()))
+}
+
+/// Decode the runestone carried by `tx`, if any.
+///
+/// A cenotaph is reported as an error since it burns every rune spent by the transaction.
+pub fn decode_runestone(tx: &Transaction) -> Result<Option<Runestone>> {
+    let Some(script) = tx.output.iter().map(|o| &o.script_pubkey).find(|script| {
+        is_op_return(script) && script.as_bytes().get(1) == Some(&OP_PUSHNUM_13.to_u8())
+    }) else {
+        return Ok(None);
+    };
+
+    let mut payload = vec![];
+    for instruction in script.instructions().skip(2) {
+        match instruction {
+            Ok(Instruction::PushBytes(bytes)) => payload.extend_from_slice(bytes.as_bytes()),
+            _ => {
+                return Err(ExchangeError::InvalidRunestone(
+                    "opcode in runestone payload".to_string(),
+                ))
+            }
+        }
+    }
+
+    let mut integers = vec![];
+    let mut i = 0;
+    while i < payload.len() {
+        let (n, len) = decode_varint(&payload[i..])?;
+        integers.push(n);
+        i += len;
+    }
+
+    let mut runestone = Runestone::default();
+    let mut fields = integers.into_iter();
+    while let Some(tag) = fields.next() {
+        if tag == TAG_BODY {
+            break;
+        }
+        let value = fields.next().ok_or(ExchangeError::InvalidRunestone(
+            "truncated field".to_string(),
+        ))?;
+        if tag == TAG_POINTER {
+            if runestone.pointer.is_none() {
+                runestone.pointer = Some(u32::try_from(value).map_err(|_| {
+                    ExchangeError::InvalidRunestone("invalid pointer".to_string())
+                })?);
+            }
+        } else if tag % 2 == 0 && !matches!(tag, 2 | 4 | 6 | 8 | 10 | 12 | 14 | 16 | 18 | 20) {
+            return Err(ExchangeError::InvalidRunestone(format!(
+                "unrecognized even tag {}",
+                tag
+            )));
+        }
+    }
+
+    let body: Vec<u128> = fields.collect();
+    (body.len() % 4 == 0)
+        .then(|| ())
+        .ok_or(ExchangeError::InvalidRunestone("truncated edict".to_string()))?;
+    let (mut block, mut tx_index) = (0u64, 0u32);
+    for edict in body.chunks(4) {
+        let block_delta = u64::try_from(edict[0])
+            .map_err(|_| ExchangeError::InvalidRunestone("invalid edict id".to_string()))?;
+        let tx_delta = u32::try_from(edict[1])
+            .map_err(|_| ExchangeError::InvalidRunestone("invalid edict id".to_string()))?;
+        if block_delta == 0 {
+            tx_index = tx_index
+                .checked_add(tx_delta)
+                .ok_or(ExchangeError::InvalidRunestone("invalid edict id".to_string()))?;
+        } else {
+            block = block
+                .checked_add(block_delta)
+                .ok_or(ExchangeError::InvalidRunestone("invalid edict id".to_string()))?;
+            tx_index = tx_delta;
+        }
+        let output = u32::try_from(edict[3])
+            .ok()
+            .filter(|output| (*output as usize) <= tx.output.len())
+            .ok_or(ExchangeError::InvalidRunestone("invalid edict output".to_string()))?;
+        runestone.edicts.push(Edict {
+            id: CoinId {
+                block,
+                tx: tx_index,
+            },
+            amount: edict[2],
+            output,
+        });
+    }
+
+    if let Some(pointer) = runestone.pointer {
+        ((pointer as usize) < tx.output.len())
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidRunestone("invalid pointer".to_string()))?;
+    }
+    Ok(Some(runestone))
+}
+
+/// Work out how many of `rune_id` each output of `tx` receives when `rune_input`
+/// of them are spent, following the runestone edicts and pointer.
+pub fn allocate_runes(
+    tx: &Transaction,
+    rune_id: CoinId,
+    rune_input: u128,
+) -> Result<BTreeMap<u32, u128>> {
+    let mut allocation = BTreeMap::new();
+    let mut unallocated = rune_input;
+    let runestone = decode_runestone(tx)?.unwrap_or_default();
+    let spendable: Vec<u32> = tx
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, o)| !is_op_return(&o.script_pubkey))
+        .map(|(i, _)| i as u32)
+        .collect();
+
+    let mut allocate = |output: u32, amount: u128, unallocated: &mut u128| {
+        let amount = amount.min(*unallocated);
+        *unallocated -= amount;
+        *allocation.entry(output).or_insert(0) += amount;
+    };
+
+    for edict in runestone.edicts.iter().filter(|e| e.id == rune_id) {
+        if edict.output as usize == tx.output.len() {
+            if spendable.is_empty() {
+                continue;
+            }
+            if edict.amount == 0 {
+                let count = spendable.len() as u128;
+                let amount = unallocated / count;
+                let remainder = unallocated % count;
+                for (i, output) in spendable.iter().enumerate() {
+                    let extra = if (i as u128) < remainder { 1 } else { 0 };
+                    allocate(*output, amount + extra, &mut unallocated);
+                }
+            } else {
+                for output in spendable.iter() {
+                    allocate(*output, edict.amount, &mut unallocated);
+                }
+            }
+        } else {
+            let amount = if edict.amount == 0 {
+                unallocated
+            } else {
+                edict.amount
+            };
+            allocate(edict.output, amount, &mut unallocated);
+        }
+    }
+
+    if unallocated > 0 {
+        if let Some(output) = runestone.pointer.or(spendable.first().cloned()) {
+            allocate(output, unallocated, &mut unallocated);
+        }
+    }
+    allocation.retain(|_, amount| *amount > 0);
+
+    Ok(allocation)
+}
+
+/// Check the outputs of `psbt` against the pool state the intention would produce.
+///
+/// Every utxo of `new_state` must be created by this very transaction with the pool's script
+/// and the claimed value, the runes spent from the pool must end up in the new rune utxo and
+/// the `output_coins` recipients, and the recipients must be paid the claimed btc.
+pub(crate) fn check_outputs(
+    psbt: &Psbt,
+    txid: Txid,
+    pool_address: &str,
+    network: Network,
+    consumed: &[Utxo],
+    new_state: &PoolState,
+    output_coins: &[OutputCoin],
+) -> Result<()> {
+    let tx = &psbt.unsigned_tx;
+    (tx.compute_txid() == Into::<bitcoin::Txid>::into(txid))
+        .then(|| ())
+        .ok_or(ExchangeError::InvalidPsbt(format!(
+            "txid not match, expect {}, got {}",
+            txid,
+            tx.compute_txid()
+        )))?;
+
+    let pool_script = address_to_script(pool_address, network)?;
+    for utxo in new_state.utxos() {
+        let output = (Into::<bitcoin::Txid>::into(utxo.txid) == tx.compute_txid())
+            .then(|| tx.output.get(utxo.vout as usize))
+            .flatten()
+            .ok_or(ExchangeError::PoolOutputNotFound(utxo.outpoint()))?;
+        (output.script_pubkey == pool_script)
+            .then(|| ())
+            .ok_or(ExchangeError::PsbtOutputMismatch(format!(
+                "output {} is not paid to the pool",
+                utxo.outpoint()
+            )))?;
+        (output.value.to_sat() == utxo.sats)
+            .then(|| ())
+            .ok_or(ExchangeError::PsbtOutputMismatch(format!(
+                "output {} value expect {}, got {}",
+                utxo.outpoint(),
+                utxo.sats,
+                output.value.to_sat()
+            )))?;
+    }
+
+    for output_coin in output_coins.iter() {
+        let script = address_to_script(&output_coin.to, network)?;
+        let vouts: Vec<u32> = tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| o.script_pubkey == script)
+            .map(|(i, _)| i as u32)
+            .collect();
+        if output_coin.coin.id == CoinId::btc() {
+            let paid: u128 = vouts
+                .iter()
+                .map(|vout| tx.output[*vout as usize].value.to_sat() as u128)
+                .sum();
+            (paid >= output_coin.coin.value)
+                .then(|| ())
+                .ok_or(ExchangeError::PsbtOutputMismatch(format!(
+                    "{} expect {} sats, got {}",
+                    output_coin.to, output_coin.coin.value, paid
+                )))?;
+        }
+    }
+
+    let rune_ids: Vec<CoinId> = consumed
+        .iter()
+        .filter_map(|utxo| utxo.maybe_rune.clone().map(|rune| rune.id))
+        .collect();
+    for rune_id in rune_ids {
+        let rune_input: u128 = consumed
+            .iter()
+            .filter_map(|utxo| utxo.maybe_rune.clone())
+            .filter(|rune| rune.id == rune_id)
+            .map(|rune| rune.value)
+            .sum();
+        let allocation = allocate_runes(tx, rune_id, rune_input)?;
+
+        let pool_expected = new_state
+            .rune_utxo
+            .as_ref()
+            .and_then(|utxo| utxo.maybe_rune.clone().map(|rune| (utxo.vout, rune)))
+            .filter(|(_, rune)| rune.id == rune_id);
+        let pool_received = pool_expected
+            .as_ref()
+            .map(|(vout, _)| allocation.get(vout).cloned().unwrap_or(0))
+            .unwrap_or(0);
+        let pool_expected = pool_expected.map(|(_, rune)| rune.value).unwrap_or(0);
+        (pool_received == pool_expected)
+            .then(|| ())
+            .ok_or(ExchangeError::PsbtOutputMismatch(format!(
+                "pool rune utxo expect {} of {}, got {}",
+                pool_expected, rune_id, pool_received
+            )))?;
+
+        for output_coin in output_coins.iter().filter(|c| c.coin.id == rune_id) {
+            let script = address_to_script(&output_coin.to, network)?;
+            let received: u128 = allocation
+                .iter()
+                .filter(|(vout, _)| tx.output[**vout as usize].script_pubkey == script)
+                .map(|(_, amount)| *amount)
+                .sum();
+            (received == output_coin.coin.value)
+                .then(|| ())
+                .ok_or(ExchangeError::PsbtOutputMismatch(format!(
+                    "{} expect {} of {}, got {}",
+                    output_coin.to, output_coin.coin.value, rune_id, received
+                )))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn mock_tx(outputs: Vec<ScriptBuf>) -> Transaction {
+    mock_tx_with_values(outputs.into_iter().map(|script| (script, 546)).collect())
+}
+
+#[cfg(test)]
+fn mock_tx_with_values(outputs: Vec<(ScriptBuf, u64)>) -> Transaction {
+    use ree_types::bitcoin::{absolute::LockTime, transaction::Version, Amount, TxOut};
+    Transaction {
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+        input: vec![],
+        output: outputs
+            .into_iter()
+            .map(|(script_pubkey, sats)| TxOut {
+                value: Amount::from_sat(sats),
+                script_pubkey,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+fn mock_runestone_script(integers: Vec<u128>) -> ScriptBuf {
+    use ree_types::bitcoin::script::{Builder, PushBytesBuf};
+    let mut payload = vec![];
+    for mut n in integers {
+        while n >> 7 > 0 {
+            payload.push((n & 0b0111_1111) as u8 | 0b1000_0000);
+            n >>= 7;
+        }
+        payload.push(n as u8);
+    }
+    Builder::new()
+        .push_opcode(OP_RETURN)
+        .push_opcode(OP_PUSHNUM_13)
+        .push_slice(PushBytesBuf::try_from(payload).unwrap())
+        .into_script()
+}
+
+#[test]
+pub fn test_allocate_runes_by_edicts() {
+    let rune_id = CoinId {
+        block: 840000,
+        tx: 3,
+    };
+    let tx = mock_tx(vec![
+        mock_runestone_script(vec![TAG_BODY, 840000, 3, 100, 1, 0, 0, 20, 2]),
+        ScriptBuf::from_hex("0014a5108b8719d4900383c7c2713dd8470028e747b0").unwrap(),
+        ScriptBuf::from_hex("0014a5108b8719d4900383c7c2713dd8470028e747b1").unwrap(),
+        ScriptBuf::from_hex("0014a5108b8719d4900383c7c2713dd8470028e747b2").unwrap(),
+    ]);
+
+    let runestone = decode_runestone(&tx).unwrap().unwrap();
+    assert_eq!(runestone.edicts.len(), 2);
+    assert_eq!(runestone.edicts[1].id, rune_id);
+
+    let allocation = allocate_runes(&tx, rune_id, 1000).unwrap();
+    assert_eq!(allocation.get(&1), Some(&980));
+    assert_eq!(allocation.get(&2), Some(&20));
+    assert_eq!(allocation.get(&3), None);
+}
+
+#[test]
+pub fn test_allocate_runes_to_pointer() {
+    let rune_id = CoinId {
+        block: 840000,
+        tx: 3,
+    };
+    let tx = mock_tx(vec![
+        ScriptBuf::from_hex("0014a5108b8719d4900383c7c2713dd8470028e747b0").unwrap(),
+        mock_runestone_script(vec![TAG_POINTER, 2, TAG_BODY, 840000, 3, 300, 0]),
+        ScriptBuf::from_hex("0014a5108b8719d4900383c7c2713dd8470028e747b2").unwrap(),
+    ]);
+
+    let allocation = allocate_runes(&tx, rune_id, 1000).unwrap();
+    assert_eq!(allocation.get(&0), Some(&300));
+    assert_eq!(allocation.get(&2), Some(&700));
+}
+
+#[test]
+pub fn test_cenotaph_rejected() {
+    let tx = mock_tx(vec![mock_runestone_script(vec![TAG_BODY, 840000, 3, 100, 5])]);
+    assert!(decode_runestone(&tx).is_err());
+}
+
+#[cfg(test)]
+const MOCK_RUNE_ID: CoinId = CoinId {
+    block: 840000,
+    tx: 3,
+};
+
+#[cfg(test)]
+fn mock_pool_script() -> ScriptBuf {
+    ScriptBuf::from_hex("512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+        .unwrap()
+}
+
+#[cfg(test)]
+fn mock_address(script: &ScriptBuf) -> String {
+    bitcoin::Address::from_script(script, Network::Testnet4)
+        .unwrap()
+        .to_string()
+}
+
+/// A utxo the pool held before the tx, `vout` of an earlier tx.
+#[cfg(test)]
+fn mock_spent_utxo(vout: u32, sats: u64, runes: Option<u128>) -> Utxo {
+    let rune = runes.map(|value| ree_types::CoinBalance {
+        id: MOCK_RUNE_ID,
+        value,
+    });
+    Utxo::try_from(format!("{:064x}:{}", 1, vout), rune, sats).unwrap()
+}
+
+/// Build a tx with `outputs` and check it against the pool state `new_state` builds from
+/// the txid, since every utxo of the new state is an output of the tx.
+#[cfg(test)]
+fn check_mock_outputs(
+    outputs: Vec<(ScriptBuf, u64)>,
+    consumed: &[Utxo],
+    new_state: impl Fn(&Txid) -> PoolState,
+    output_coins: &[OutputCoin],
+) -> Result<()> {
+    let tx = mock_tx_with_values(outputs);
+    let txid = Txid::from_str(&tx.compute_txid().to_string()).unwrap();
+    let new_state = new_state(&txid);
+    check_outputs(
+        &Psbt::from_unsigned_tx(tx).unwrap(),
+        txid,
+        &mock_address(&mock_pool_script()),
+        Network::Testnet4,
+        consumed,
+        &new_state,
+        output_coins,
+    )
+}
+
+#[cfg(test)]
+fn mock_new_state(txid: &Txid, utxo: (u32, u64), rune_utxo: Option<(u32, u64, u128)>) -> PoolState {
+    PoolState {
+        id: Some(*txid),
+        nonce: 1,
+        utxo: Utxo::try_from(format!("{}:{}", txid, utxo.0), None, utxo.1).unwrap(),
+        rune_utxo: rune_utxo.map(|(vout, sats, value)| {
+            Utxo::try_from(
+                format!("{}:{}", txid, vout),
+                Some(ree_types::CoinBalance {
+                    id: MOCK_RUNE_ID,
+                    value,
+                }),
+                sats,
+            )
+            .unwrap()
+        }),
+        rune_balance: rune_utxo.map(|(_, _, value)| value).unwrap_or_default(),
+        user_action: crate::state::UserAction::AddLiquidity,
+    }
+}
+
+#[test]
+pub fn test_check_register_outputs() {
+    let pool = mock_pool_script();
+    let gamer = ScriptBuf::from_hex("0014a5108b8719d4900383c7c2713dd8470028e747b0").unwrap();
+    let consumed = vec![mock_spent_utxo(0, 10000, None)];
+    let new_state = |vout| move |txid: &Txid| mock_new_state(txid, (vout, 20000), None);
+
+    check_mock_outputs(vec![(pool.clone(), 20000)], &consumed, new_state(0), &[]).unwrap();
+
+    // the fee paid to the gamer instead of the pool
+    let err = check_mock_outputs(vec![(gamer, 20000)], &consumed, new_state(0), &[]);
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+    // the pool paid less than the claimed value
+    let err = check_mock_outputs(vec![(pool.clone(), 19999)], &consumed, new_state(0), &[]);
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+    // `pool_utxo_receive` points to an output the tx doesn't have
+    let err = check_mock_outputs(vec![(pool, 20000)], &consumed, new_state(1), &[]);
+    assert!(matches!(err, Err(ExchangeError::PoolOutputNotFound(_))));
+    // register spends no runes, so there is no edict to check
+}
+
+#[test]
+pub fn test_check_add_liquidity_outputs() {
+    let pool = mock_pool_script();
+    let richswap =
+        ScriptBuf::from_hex("5120c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5")
+            .unwrap();
+    let consumed = vec![
+        mock_spent_utxo(0, 30000, None),
+        mock_spent_utxo(1, 546, Some(1000)),
+    ];
+    let output_coins = [
+        OutputCoin {
+            coin: ree_types::CoinBalance {
+                id: CoinId::btc(),
+                value: 20000,
+            },
+            to: mock_address(&richswap),
+        },
+        OutputCoin {
+            coin: ree_types::CoinBalance {
+                id: MOCK_RUNE_ID,
+                value: 600,
+            },
+            to: mock_address(&richswap),
+        },
+    ];
+    let new_state = |rune_vout| {
+        move |txid: &Txid| mock_new_state(txid, (0, 10000), Some((rune_vout, 546, 400)))
+    };
+    // the runes left in the pool go to the pointer, the rest to richswap
+    let outputs = |btc: (ScriptBuf, u64), edict: u128| {
+        vec![
+            btc,
+            (pool.clone(), 546),
+            (richswap.clone(), 20000),
+            (richswap.clone(), 546),
+            (
+                mock_runestone_script(vec![TAG_POINTER, 1, TAG_BODY, 840000, 3, edict, 3]),
+                0,
+            ),
+        ]
+    };
+
+    check_mock_outputs(
+        outputs((pool.clone(), 10000), 600),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    )
+    .unwrap();
+
+    let err = check_mock_outputs(
+        outputs((richswap.clone(), 10000), 600),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+    let err = check_mock_outputs(
+        outputs((pool.clone(), 9999), 600),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+    let err = check_mock_outputs(
+        outputs((pool.clone(), 10000), 600),
+        &consumed,
+        new_state(5),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PoolOutputNotFound(_))));
+    // the edict moves 100 runes of the pool to richswap
+    let err = check_mock_outputs(
+        outputs((pool.clone(), 10000), 700),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+}
+
+#[test]
+pub fn test_check_withdraw_outputs() {
+    let pool = mock_pool_script();
+    let gamer = ScriptBuf::from_hex("0014a5108b8719d4900383c7c2713dd8470028e747b0").unwrap();
+    let consumed = vec![
+        mock_spent_utxo(0, 10000, None),
+        mock_spent_utxo(1, 546, Some(400)),
+    ];
+    let output_coins = [OutputCoin {
+        coin: ree_types::CoinBalance {
+            id: MOCK_RUNE_ID,
+            value: 100,
+        },
+        to: mock_address(&gamer),
+    }];
+    let new_state = |rune_vout| {
+        move |txid: &Txid| mock_new_state(txid, (0, 10000), Some((rune_vout, 546, 300)))
+    };
+    // the runes left in the pool go to the pointer, the cookies of the gamer to the gamer
+    let outputs = |btc: (ScriptBuf, u64), edict: u128| {
+        vec![
+            btc,
+            (pool.clone(), 546),
+            (gamer.clone(), 546),
+            (
+                mock_runestone_script(vec![TAG_POINTER, 1, TAG_BODY, 840000, 3, edict, 2]),
+                0,
+            ),
+        ]
+    };
+
+    check_mock_outputs(
+        outputs((pool.clone(), 10000), 100),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    )
+    .unwrap();
+
+    let err = check_mock_outputs(
+        outputs((gamer.clone(), 10000), 100),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+    let err = check_mock_outputs(
+        outputs((pool.clone(), 10001), 100),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+    let err = check_mock_outputs(
+        outputs((pool.clone(), 10000), 100),
+        &consumed,
+        new_state(4),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PoolOutputNotFound(_))));
+    // the edict pays the gamer more than the cookies withdrawn
+    let err = check_mock_outputs(
+        outputs((pool.clone(), 10000), 150),
+        &consumed,
+        new_state(1),
+        &output_coins,
+    );
+    assert!(matches!(err, Err(ExchangeError::PsbtOutputMismatch(_))));
+}
@@ -0,0 +1,105 @@
+pub mod inspect;
+
+use crate::{
+    external::management::{request_schnorr_key, sign_prehash_with_schnorr},
+    memory::{read_pool, read_state},
+    ExchangeError, PoolAddress, Result, Utxo,
+};
+use futures_util::future::try_join_all;
+use ree_types::bitcoin::{
+    self,
+    psbt::Psbt,
+    sighash::{Prevouts, SighashCache},
+    OutPoint, TapSighashType, Witness,
+};
+
+thread_local! {
+    /// The pools whose key has been checked against the key derived from the stored key id
+    /// and path. It lives on the heap, so the keys are checked again after an upgrade which
+    /// could have changed the key id.
+    static VERIFIED_POOL_KEYS: std::cell::RefCell<std::collections::BTreeSet<PoolAddress>> =
+        std::cell::RefCell::new(std::collections::BTreeSet::new());
+}
+
+/// Check once per pool that the key derived from the stored key id and path is the pool key.
+async fn verify_pool_key(pool: &str) -> Result<()> {
+    if VERIFIED_POOL_KEYS.with_borrow(|v| v.contains(pool)) {
+        return Ok(());
+    }
+    let key_id = read_state(|s| s.key_id.clone());
+    let (path, pool_key) = read_pool(pool, |p| (p.key_derivation_path.clone(), p.key.clone()))?;
+    let derived_key = request_schnorr_key(key_id, path).await?;
+    (derived_key == pool_key)
+        .then(|| ())
+        .ok_or(ExchangeError::PoolKeyMismatch(
+            pool_key.to_string(),
+            derived_key.to_string(),
+        ))?;
+    VERIFIED_POOL_KEYS.with_borrow_mut(|v| v.insert(pool.to_string()));
+    Ok(())
+}
+
+fn cmp<'a>(mine: &'a Utxo, outpoint: &OutPoint) -> Option<&'a Utxo> {
+    (Into::<bitcoin::Txid>::into(mine.txid) == outpoint.txid && mine.vout == outpoint.vout)
+        .then(|| mine)
+}
+
+/// Sign every input of `psbt` which spends one of `pool_inputs` with the key of `pool`.
+///
+/// All the sighashes are computed up front and the signing requests are sent to the
+/// management canister concurrently. Fails if any of the pool inputs is not spent by the psbt,
+/// or if the key derived from the stored key id and path is not the pool key.
+pub(crate) async fn sign(psbt: &mut Psbt, pool: &str, pool_inputs: &[Utxo]) -> Result<()> {
+    verify_pool_key(pool).await?;
+    let key_id = read_state(|s| s.key_id.clone());
+    let path = read_pool(pool, |p| p.key_derivation_path.clone())?;
+
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    let mut prevouts = vec![];
+    for input in psbt.inputs.iter() {
+        let pout = input
+            .witness_utxo
+            .as_ref()
+            .cloned()
+            .ok_or(ExchangeError::InvalidPsbt("witness_utxo required".to_string()))?;
+        prevouts.push(pout);
+    }
+
+    let mut to_sign = vec![];
+    for pool_input in pool_inputs.iter() {
+        let i = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .position(|input| cmp(pool_input, &input.previous_output).is_some())
+            .ok_or(ExchangeError::InvalidPsbt(format!(
+                "pool input {} not found",
+                pool_input.outpoint()
+            )))?;
+        (i < psbt.inputs.len())
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidPsbt("inputs not enough".to_string()))?;
+        let sighash = cache
+            .taproot_key_spend_signature_hash(i, &Prevouts::All(&prevouts), TapSighashType::Default)
+            .map_err(|e| ExchangeError::InvalidPsbt(e.to_string()))?;
+        to_sign.push((i, sighash));
+    }
+
+    let raw_sigs = try_join_all(
+        to_sign
+            .iter()
+            .map(|(_, sighash)| sign_prehash_with_schnorr(sighash, key_id.clone(), path.clone())),
+    )
+    .await?;
+
+    for ((i, _), raw_sig) in to_sign.into_iter().zip(raw_sigs.into_iter()) {
+        let inner_sig = bitcoin::secp256k1::schnorr::Signature::from_slice(&raw_sig)
+            .map_err(|_| ExchangeError::ChainKeyError)?;
+        let signature = bitcoin::taproot::Signature {
+            signature: inner_sig,
+            sighash_type: TapSighashType::Default,
+        };
+        psbt.inputs[i].final_script_witness = Some(Witness::p2tr_key_spend(&signature));
+    }
+    Ok(())
+}
@@ -1,4 +1,8 @@
-use crate::{memory::{BLOCKS, TX_RECORDS}, *};
+use crate::{
+    event::{record_pool_event, EventKind},
+    memory::{pool_addresses, read_pool, BLOCKS, BLOCK_GAPS, TX_RECORDS},
+    *,
+};
 use ree_types::{bitcoin::Network, exchange_interfaces::NewBlockInfo};
 use thiserror::Error;
 
@@ -14,7 +18,13 @@ pub enum ReorgError {
     Unrecoverable,
 
     #[error("block not found in state at height {height}")]
-    BlockNotFoundInState {height: u32}
+    BlockNotFoundInState {height: u32},
+
+    #[error("blocks missing from height {from} to {to}")]
+    Gap { from: u32, to: u32 },
+
+    #[error("block fills the gap at height {height}")]
+    FillsGap { height: u32 },
 }
 
 pub fn get_max_recoverable_reorg_depth(network: Network) -> u32 {
@@ -29,7 +39,8 @@ pub fn get_max_recoverable_reorg_depth(network: Network) -> u32 {
 }
 
 pub(crate) fn detect_reorg(network: Network, new_block: NewBlockInfo) -> std::result::Result<(), ReorgError> {
-    ic_cdk::println!(
+    log!(
+        DEBUG,
         "Processing new block - height: {}, hash: {}, timestamp: {}, confirmed_txs: {:?}",
         new_block.block_height,
         new_block.block_hash,
@@ -39,34 +50,43 @@ pub(crate) fn detect_reorg(network: Network, new_block: NewBlockInfo) -> std::re
     let current_block = BLOCKS.with_borrow(|m| m.iter().rev().next().map(|(_height, block)| block));
     match current_block {
         None => {
-            ic_cdk::println!("No blocks found in exchange - this is expected for new exchanges");
+            log!(DEBUG, "No blocks found in exchange - this is expected for new exchanges");
             return Ok(());
         }
         Some(current_block) => {
-            ic_cdk::println!(
+            log!(
+                DEBUG,
                 "Current block - height: {:?}, hash: {:?}, timestamp: {:?}",
                 current_block.block_height,
                 current_block.block_hash,
                 current_block.block_timestamp
             );
             if new_block.block_height == current_block.block_height + 1 {
-                ic_cdk::println!("New block is the next block in the chain");
+                log!(DEBUG, "New block is the next block in the chain");
                 return Ok(());
             } else if new_block.block_height > current_block.block_height + 1 {
-                ic_cdk::println!("New block is more than one block ahead of the current block");
-                return Err(ReorgError::Unrecoverable);
+                log!(DEBUG, "New block is more than one block ahead of the current block");
+                return Err(ReorgError::Gap {
+                    from: current_block.block_height + 1,
+                    to: new_block.block_height - 1,
+                });
+            } else if is_missing(new_block.block_height) {
+                log!(DEBUG, "New block fills a gap in the chain");
+                return Err(ReorgError::FillsGap {
+                    height: new_block.block_height,
+                });
             } else {
                 let reorg_depth = current_block.block_height - new_block.block_height + 1;
-                ic_cdk::println!("Detected reorg - depth: {}", reorg_depth,);
+                log!(INFO, "Detected reorg - depth: {}", reorg_depth);
                 if reorg_depth > get_max_recoverable_reorg_depth(network) {
-                    ic_cdk::println!("Reorg depth is greater than the max recoverable reorg depth");
+                    log!(WARNING, "Reorg depth is greater than the max recoverable reorg depth");
                     return Err(ReorgError::Unrecoverable);
                 }
                 let target_block = BLOCKS.with_borrow(|m| m.get(&new_block.block_height).ok_or(
                     ReorgError::BlockNotFoundInState { height: new_block.block_height }
                 ))?;
                 if target_block.block_hash == new_block.block_hash {
-                    ic_cdk::println!("New block is a duplicate block");
+                    log!(INFO, "New block is a duplicate block");
                     return Err(ReorgError::DuplicateBlock {
                         height: new_block.block_height,
                         hash: new_block.block_hash,
@@ -81,25 +101,339 @@ pub(crate) fn detect_reorg(network: Network, new_block: NewBlockInfo) -> std::re
     }
 }
 
-pub fn handle_reorg(height: u32, depth: u32) {
-    ic_cdk::println!("Rolling back state after reorg of depth {depth} at height {height}");
+/// Unconfirm the txs of the orphaned blocks, and return the txids of the pool states which
+/// depend on them in any of the pools.
+///
+/// The pool states are only finalized deeper than the max recoverable reorg depth, so they are
+/// still pending here: they stay pending until their txs are confirmed in the new chain, or are
+/// unwound together with their game side effects when the orchestrator rolls the txs back.
+pub fn handle_reorg(height: u32, depth: u32) -> Vec<Txid> {
+    log!(INFO, "Rolling back state after reorg of depth {depth} at height {height}");
+
+    let orphaned = unconfirm_orphaned_blocks(height, depth);
+    let mut dependents = vec![];
+    for pool in pool_addresses() {
+        let Ok(pool_dependents) = read_pool(&pool, |p| p.states_depending_on(&orphaned)) else {
+            continue;
+        };
+        for txid in orphaned.iter().filter(|txid| pool_dependents.contains(txid)) {
+            record_pool_event(&pool, EventKind::TxReorged { txid: txid.clone() });
+        }
+        dependents.extend(pool_dependents);
+    }
+    if !dependents.is_empty() {
+        log!(
+            WARNING,
+            "pool states pending on the orphaned txs: {:?}",
+            dependents
+        );
+    }
 
+    log!(
+        INFO,
+        "Successfully rolled back state to height {}",
+        height - depth,
+    );
+    dependents
+}
+
+/// Remove the `depth` blocks up to `height` and move their txs back to unconfirmed,
+/// returns the txids which were confirmed in them.
+fn unconfirm_orphaned_blocks(height: u32, depth: u32) -> Vec<Txid> {
+    let mut orphaned = vec![];
     for h in (height - depth + 1..=height).rev() {
-        ic_cdk::println!("Rolling back change record at height {h}");
-        let block = BLOCKS.with_borrow(|m| m.get(&h).unwrap());
+        log!(INFO, "Rolling back change record at height {h}");
+        // a height missing from the feed has nothing to unconfirm
+        let Some(block) = BLOCKS.with_borrow(|m| m.get(&h)) else {
+            continue;
+        };
         for txid in block.confirmed_txids.iter() {
             TX_RECORDS.with_borrow_mut(|m| {
                 if let Some(record) = m.remove(&(txid.clone(), true)) {
                     m.insert((txid.clone(), false), record);
-                    ic_cdk::println!("Unconfirm txid: {}", txid);
+                    log!(INFO, "Unconfirm txid: {}", txid);
+                    orphaned.push(txid.clone());
                 }
             });
         }
         BLOCKS.with_borrow_mut(|m| m.remove(&h));
     }
+    // the orphaned heights will be fed again from the new chain
+    discard_gaps_from(height - depth + 1);
+    orphaned
+}
 
-    ic_cdk::println!(
-        "Successfully rolled back state to height {}",
-        height - depth,
+/// Whether `height` was skipped by the block feed and hasn't been filled yet.
+pub fn is_missing(height: u32) -> bool {
+    BLOCK_GAPS.with_borrow(|g| {
+        g.range(..=height)
+            .next_back()
+            .map_or(false, |(_, to)| to >= height)
+    })
+}
+
+/// The ranges of the heights skipped by the block feed, as `(from, to)` inclusive.
+pub fn get_block_gaps() -> Vec<(u32, u32)> {
+    BLOCK_GAPS.with_borrow(|g| g.iter().collect())
+}
+
+pub(crate) fn record_gap(from: u32, to: u32) {
+    log!(WARNING, "blocks missing from height {} to {}", from, to);
+    BLOCK_GAPS.with_borrow_mut(|g| g.insert(from, to));
+}
+
+/// Remove `height` from the gap containing it, splitting the gap when needed.
+pub(crate) fn fill_gap(height: u32) {
+    BLOCK_GAPS.with_borrow_mut(|g| {
+        let Some((from, to)) = g.range(..=height).next_back().filter(|(_, to)| *to >= height)
+        else {
+            return;
+        };
+        g.remove(&from);
+        if from < height {
+            g.insert(from, height - 1);
+        }
+        if height < to {
+            g.insert(height + 1, to);
+        }
+    });
+    log!(INFO, "gap at height {} filled", height);
+}
+
+/// Drop the gaps at or above `height`.
+fn discard_gaps_from(height: u32) {
+    BLOCK_GAPS.with_borrow_mut(|g| {
+        let gaps: Vec<(u32, u32)> = g.iter().filter(|(_, to)| *to >= height).collect();
+        for (from, _) in gaps {
+            g.remove(&from);
+            if from < height {
+                g.insert(from, height - 1);
+            }
+        }
+    });
+}
+
+/// Drop the gap starting at `from` without its blocks, once an operator has checked that no
+/// tx of a hosted pool was confirmed in them. The blocks below the next gap are finalized by
+/// the next `new_block`.
+pub(crate) fn accept_gap(from: u32) -> Result<()> {
+    let to = BLOCK_GAPS
+        .with_borrow_mut(|g| g.remove(&from))
+        .ok_or(ExchangeError::BlockGapNotFound(from))?;
+    log!(WARNING, "accepted the blocks missing from height {} to {}", from, to);
+    Ok(())
+}
+
+pub(crate) fn clear_gaps() {
+    BLOCK_GAPS.with_borrow_mut(|g| g.clear_new());
+}
+
+/// Blocks are only finalized below the lowest gap, the txs confirmed in a missing block are
+/// unknown so the states after them can't be finalized safely.
+pub(crate) fn finalizable_height(confirmed_height: u32) -> u32 {
+    BLOCK_GAPS.with_borrow(|g| {
+        g.first_key_value()
+            .map_or(confirmed_height, |(from, _)| confirmed_height.min(from - 1))
+    })
+}
+
+#[test]
+pub fn test_reorg_keeps_dependent_states_pending_and_rollback_unwinds_them() {
+    use crate::canister::{new_block, rollback_tx};
+    use crate::fixtures::{mock_block, mock_chain, mock_init_args, mock_txid};
+    use crate::game::gamer::get_gamer;
+    use crate::memory::{get_pool, insert_pool, set_state, ADDRESS_PRINCIPLE_MAP};
+    use crate::state::ExchangeState;
+    use ree_types::exchange_interfaces::RollbackTxArgs;
+
+    set_state(ExchangeState::init(mock_init_args()));
+    let (base, n) = (100, 6);
+    for depth in 1..=n {
+        insert_pool(mock_chain(base, n));
+        // the new chain forks off below the orphaned blocks and confirms none of their txs
+        let mut fork = mock_block(base + n - depth + 1, vec![]);
+        fork.block_hash = "fork".to_string();
+        new_block(fork).unwrap();
+
+        let orphaned: Vec<Txid> = (n - depth + 1..=n).map(mock_txid).collect();
+        assert_eq!(BLOCKS.with_borrow(|m| m.len()), (n - depth + 1) as u64);
+        TX_RECORDS.with_borrow(|m| {
+            for i in 1..=n {
+                let confirmed = i <= n - depth;
+                assert_eq!(m.contains_key(&(mock_txid(i), true)), confirmed);
+                assert_eq!(m.contains_key(&(mock_txid(i), false)), !confirmed);
+            }
+        });
+
+        // the orphaned states and everything built on them stay pending
+        let pool = get_pool("pool").unwrap();
+        assert_eq!(pool.states_depending_on(&orphaned), orphaned);
+        assert_eq!(pool.pending.len() as u32, n);
+
+        // the orchestrator drops the orphaned txs
+        rollback_tx(RollbackTxArgs {
+            txid: mock_txid(n - depth + 1),
+        })
+        .unwrap();
+        let pool = get_pool("pool").unwrap();
+        assert_eq!(pool.pending.len() as u32, n - depth);
+        assert_eq!(pool.game.claimed_cookies, 10 * (n - depth) as u128);
+        for i in 1..=n {
+            let kept = i <= n - depth;
+            let gamer = format!("gamer_{}", i);
+            assert_eq!(get_gamer("pool", &gamer).is_some(), kept);
+            assert_eq!(
+                ADDRESS_PRINCIPLE_MAP
+                    .with_borrow(|m| m.contains_key(&Principal::from_slice(&[i as u8]))),
+                kept
+            );
+        }
+        assert!(pool.states_depending_on(&orphaned).is_empty());
+    }
+}
+
+#[test]
+pub fn test_block_gaps() {
+    BLOCK_GAPS.with_borrow_mut(|g| g.clear_new());
+    record_gap(101, 105);
+    record_gap(110, 110);
+    assert!(!is_missing(100));
+    assert!(is_missing(101) && is_missing(105) && is_missing(110));
+    assert!(!is_missing(106));
+    assert_eq!(finalizable_height(200), 100);
+    assert_eq!(finalizable_height(90), 90);
+
+    fill_gap(103);
+    assert_eq!(get_block_gaps(), vec![(101, 102), (104, 105), (110, 110)]);
+    fill_gap(101);
+    fill_gap(110);
+    fill_gap(106);
+    assert_eq!(get_block_gaps(), vec![(102, 102), (104, 105)]);
+    assert_eq!(finalizable_height(200), 101);
+
+    discard_gaps_from(105);
+    assert_eq!(get_block_gaps(), vec![(102, 102), (104, 104)]);
+    discard_gaps_from(103);
+    assert_eq!(get_block_gaps(), vec![(102, 102)]);
+    fill_gap(102);
+    assert_eq!(finalizable_height(200), 200);
+}
+
+/// Feed the blocks `from..=to` confirming no tx.
+#[cfg(test)]
+fn feed_empty_blocks(from: u32, to: u32) {
+    for height in from..=to {
+        crate::canister::new_block(crate::fixtures::mock_block(height, vec![])).unwrap();
+    }
+}
+
+/// Host the pool `pool` with a pending register tx of `gamer_{i}` for each of the `n` txs,
+/// the txs are known to the exchange but not confirmed yet.
+#[cfg(test)]
+fn mock_unconfirmed_txs(n: u32) {
+    use crate::fixtures::{mock_gamer, mock_init_args, mock_pending_states, mock_txid};
+    use crate::memory::{insert_pool, set_state};
+    use crate::state::{ExchangeState, UserAction};
+    use ree_types::TxRecord;
+
+    set_state(ExchangeState::init(mock_init_args()));
+    BLOCKS.with_borrow_mut(|m| m.clear_new());
+    TX_RECORDS.with_borrow_mut(|m| m.clear_new());
+    clear_gaps();
+    let mut pool = mock_pending_states(
+        (1..=n)
+            .map(|i| UserAction::Register(format!("gamer_{}", i)))
+            .collect(),
     );
-}
\ No newline at end of file
+    for i in 1..=n {
+        mock_gamer(&mut pool, &format!("gamer_{}", i), i as u8, 10);
+        let mut record = TxRecord::default();
+        record.pools.push("pool".to_string());
+        TX_RECORDS.with_borrow_mut(|m| m.insert((mock_txid(i), false), record));
+    }
+    insert_pool(pool);
+}
+
+#[test]
+pub fn test_new_block_finalizes_once_the_gap_is_filled() {
+    use crate::canister::new_block;
+    use crate::fixtures::{mock_block, mock_txid};
+    use crate::memory::get_pool;
+    use crate::network::BtcNetwork;
+
+    mock_unconfirmed_txs(3);
+    let depth = get_max_recoverable_reorg_depth(BtcNetwork::Testnet4.bitcoin_network());
+    let confirmed_id = || get_pool("pool").unwrap().confirmed_state.unwrap().id;
+    let pending = || get_pool("pool").unwrap().pending.len();
+
+    new_block(mock_block(100, vec![mock_txid(1)])).unwrap();
+    // the feed skips 101 and 102, the blocks after them are kept
+    new_block(mock_block(103, vec![mock_txid(3)])).unwrap();
+    assert_eq!(get_block_gaps(), vec![(101, 102)]);
+    feed_empty_blocks(104, 103 + depth);
+
+    // the blocks below the gap are finalized, the confirmed tx above it stays pending
+    assert_eq!(confirmed_id(), Some(mock_txid(1)));
+    assert_eq!(pending(), 2);
+    assert!(BLOCKS.with_borrow(|m| !m.contains_key(&100) && m.contains_key(&103)));
+    assert!(TX_RECORDS.with_borrow(|m| m.contains_key(&(mock_txid(3), true))));
+
+    // the late block 102 confirms the tx 2, 101 is still missing
+    new_block(mock_block(102, vec![mock_txid(2)])).unwrap();
+    assert_eq!(get_block_gaps(), vec![(101, 101)]);
+    assert_eq!(pending(), 2);
+
+    // filling the gap finalizes the txs 2 and 3 in nonce order
+    new_block(mock_block(101, vec![])).unwrap();
+    assert!(get_block_gaps().is_empty());
+    assert_eq!(confirmed_id(), Some(mock_txid(3)));
+    assert_eq!(pending(), 0);
+    assert!(BLOCKS.with_borrow(|m| !m.contains_key(&103)));
+}
+
+#[test]
+pub fn test_accepted_gap_resumes_finalization() {
+    use crate::canister::new_block;
+    use crate::fixtures::{mock_block, mock_txid};
+    use crate::memory::get_pool;
+    use crate::network::BtcNetwork;
+
+    mock_unconfirmed_txs(2);
+    let depth = get_max_recoverable_reorg_depth(BtcNetwork::Testnet4.bitcoin_network());
+
+    new_block(mock_block(100, vec![mock_txid(1)])).unwrap();
+    new_block(mock_block(103, vec![mock_txid(2)])).unwrap();
+    feed_empty_blocks(104, 103 + depth);
+    assert_eq!(get_pool("pool").unwrap().pending.len(), 1);
+
+    assert!(accept_gap(102).is_err());
+    accept_gap(101).unwrap();
+    assert!(get_block_gaps().is_empty());
+    new_block(mock_block(104 + depth, vec![])).unwrap();
+    let pool = get_pool("pool").unwrap();
+    assert_eq!(pool.confirmed_state.unwrap().id, Some(mock_txid(2)));
+    assert!(pool.pending.is_empty());
+}
+
+#[test]
+pub fn test_tx_confirmed_in_accepted_gap_is_finalized_by_its_descendant() {
+    use crate::canister::new_block;
+    use crate::fixtures::{mock_block, mock_txid};
+    use crate::memory::get_pool;
+    use crate::network::BtcNetwork;
+
+    mock_unconfirmed_txs(3);
+    let depth = get_max_recoverable_reorg_depth(BtcNetwork::Testnet4.bitcoin_network());
+
+    // the tx 1 is confirmed in the missing block 101
+    new_block(mock_block(100, vec![])).unwrap();
+    new_block(mock_block(102, vec![mock_txid(2)])).unwrap();
+    new_block(mock_block(103, vec![mock_txid(3)])).unwrap();
+    feed_empty_blocks(104, 103 + depth);
+    accept_gap(101).unwrap();
+
+    new_block(mock_block(104 + depth, vec![])).unwrap();
+    let pool = get_pool("pool").unwrap();
+    assert_eq!(pool.confirmed_state.unwrap().id, Some(mock_txid(3)));
+    assert!(pool.pending.is_empty());
+}
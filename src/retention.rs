@@ -0,0 +1,262 @@
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+
+use crate::memory::{
+    BLOCKS, FINALIZED_TXS, RETENTION_CONFIG, ROLLED_BACK_HEIGHTS, ROLLED_BACK_TXS, TX_RECORDS,
+};
+use crate::*;
+
+/// About a day of blocks.
+pub const DEFAULT_TX_RECORD_RETENTION_BLOCKS: u32 = 144;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct RetentionConfig {
+    /// Number of blocks the record of a finalized tx is kept after its finalization.
+    pub tx_record_retention_blocks: u32,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            tx_record_retention_blocks: DEFAULT_TX_RECORD_RETENTION_BLOCKS,
+        }
+    }
+}
+
+impl Storable for RetentionConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub fn get_retention_config() -> RetentionConfig {
+    RETENTION_CONFIG.with_borrow(|c| c.get().clone())
+}
+
+pub(crate) fn set_tx_record_retention_blocks(blocks: u32) {
+    RETENTION_CONFIG.with_borrow_mut(|c| {
+        c.set(RetentionConfig {
+            tx_record_retention_blocks: blocks,
+        })
+        .expect("Failed to set RETENTION_CONFIG")
+    });
+}
+
+/// Move the records of the txs confirmed in a block from unconfirmed to confirmed.
+pub(crate) fn confirm_txs(txids: &[Txid]) {
+    TX_RECORDS.with_borrow_mut(|m| {
+        for txid in txids {
+            if let Some(record) = m.remove(&(txid.clone(), false)) {
+                log!(INFO, "Confirm txid: {} with pools: {:?}", txid, record.pools);
+                m.insert((txid.clone(), true), record);
+            }
+        }
+    });
+}
+
+pub fn is_rolled_back(txid: &Txid) -> bool {
+    ROLLED_BACK_TXS.with_borrow(|m| m.contains_key(txid))
+}
+
+/// Drop the records of a rolled back tx so it is never confirmed or finalized, and remember
+/// it at the tip height so that rolling it back again is a no-op.
+pub(crate) fn mark_rolled_back(txid: Txid) {
+    TX_RECORDS.with_borrow_mut(|m| {
+        m.remove(&(txid.clone(), true));
+        m.remove(&(txid.clone(), false));
+    });
+    if ROLLED_BACK_TXS.with_borrow_mut(|m| m.insert(txid, ())).is_none() {
+        let tip_height = BLOCKS.with_borrow(|m| m.last_key_value().map_or(0, |(h, _)| h));
+        ROLLED_BACK_HEIGHTS.with_borrow_mut(|m| m.insert((tip_height, txid), ()));
+    }
+}
+
+/// Forget that `txid` was rolled back, called when the orchestrator executes it again.
+pub(crate) fn unmark_rolled_back(txid: &Txid) {
+    if ROLLED_BACK_TXS.with_borrow_mut(|m| m.remove(txid)).is_none() {
+        return;
+    }
+    ROLLED_BACK_HEIGHTS.with_borrow_mut(|m| {
+        let keys: Vec<(u32, Txid)> =
+            m.iter().map(|(key, _)| key).filter(|(_, t)| t == txid).collect();
+        for key in keys {
+            m.remove(&key);
+        }
+    });
+}
+
+/// Forget the txs rolled back at least `retention_blocks` blocks before `confirmed_height`,
+/// returns the number of the txs forgotten. Their records are gone, so rolling one of them
+/// back again is still a no-op.
+pub(crate) fn prune_rolled_back(confirmed_height: u32, retention_blocks: u32) -> usize {
+    let Some(prune_height) = confirmed_height.checked_sub(retention_blocks) else {
+        return 0;
+    };
+    let pruned: Vec<(u32, Txid)> = ROLLED_BACK_HEIGHTS.with_borrow(|m| {
+        m.iter()
+            .take_while(|((height, _), _)| *height <= prune_height)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    ROLLED_BACK_TXS.with_borrow_mut(|m| {
+        for (_, txid) in pruned.iter() {
+            m.remove(txid);
+        }
+    });
+    ROLLED_BACK_HEIGHTS.with_borrow_mut(|m| {
+        for key in pruned.iter() {
+            m.remove(key);
+        }
+    });
+    if !pruned.is_empty() {
+        log!(
+            INFO,
+            "forgot {} txs rolled back at or below height {}",
+            pruned.len(),
+            prune_height
+        );
+    }
+    pruned.len()
+}
+
+/// Give the txs rolled back before their heights were kept the current tip height, so that
+/// they are pruned as well.
+pub(crate) fn backfill_rolled_back_heights() {
+    let tip_height = BLOCKS.with_borrow(|m| m.last_key_value().map_or(0, |(h, _)| h));
+    let known: std::collections::BTreeSet<Txid> =
+        ROLLED_BACK_HEIGHTS.with_borrow(|m| m.iter().map(|((_, txid), _)| txid).collect());
+    let missing: Vec<Txid> = ROLLED_BACK_TXS.with_borrow(|m| {
+        m.iter()
+            .map(|(txid, _)| txid)
+            .filter(|txid| !known.contains(txid))
+            .collect()
+    });
+    ROLLED_BACK_HEIGHTS.with_borrow_mut(|m| {
+        for txid in missing {
+            m.insert((tip_height, txid), ());
+        }
+    });
+}
+
+/// Remember that `txid` was finalized in the block at `height` so its record can be pruned.
+pub(crate) fn record_finalized(height: u32, txid: Txid) {
+    FINALIZED_TXS.with_borrow_mut(|m| m.insert((height, txid), ()));
+}
+
+/// Delete the records of the txs finalized at least `retention_blocks` blocks before
+/// `confirmed_height`, returns the number of the records deleted.
+pub(crate) fn prune_finalized(confirmed_height: u32, retention_blocks: u32) -> usize {
+    let Some(prune_height) = confirmed_height.checked_sub(retention_blocks) else {
+        return 0;
+    };
+    let pruned: Vec<(u32, Txid)> = FINALIZED_TXS.with_borrow(|m| {
+        m.iter()
+            .take_while(|((height, _), _)| *height <= prune_height)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    TX_RECORDS.with_borrow_mut(|m| {
+        for (_, txid) in pruned.iter() {
+            m.remove(&(txid.clone(), true));
+            m.remove(&(txid.clone(), false));
+        }
+    });
+    FINALIZED_TXS.with_borrow_mut(|m| {
+        for key in pruned.iter() {
+            m.remove(key);
+        }
+    });
+    if !pruned.is_empty() {
+        log!(
+            INFO,
+            "pruned {} tx records finalized at or below height {}",
+            pruned.len(),
+            prune_height
+        );
+    }
+    pruned.len()
+}
+
+#[test]
+pub fn test_confirm_and_prune_tx_records() {
+    use crate::fixtures::mock_txid;
+    use ree_types::TxRecord;
+
+    TX_RECORDS.with_borrow_mut(|m| m.clear_new());
+    FINALIZED_TXS.with_borrow_mut(|m| m.clear_new());
+    for i in 1..=4 {
+        TX_RECORDS.with_borrow_mut(|m| m.insert((mock_txid(i), false), TxRecord::default()));
+    }
+
+    // confirming leaves no unconfirmed duplicates behind
+    confirm_txs(&[mock_txid(1), mock_txid(2), mock_txid(3)]);
+    TX_RECORDS.with_borrow(|m| {
+        assert_eq!(m.len(), 4);
+        for i in 1..=3 {
+            assert!(m.contains_key(&(mock_txid(i), true)));
+            assert!(!m.contains_key(&(mock_txid(i), false)));
+        }
+        assert!(m.contains_key(&(mock_txid(4), false)));
+    });
+
+    record_finalized(100, mock_txid(1));
+    record_finalized(101, mock_txid(2));
+    record_finalized(102, mock_txid(3));
+
+    assert_eq!(prune_finalized(105, 10), 0);
+    assert_eq!(prune_finalized(110, 10), 1);
+    TX_RECORDS.with_borrow(|m| {
+        assert!(!m.contains_key(&(mock_txid(1), true)));
+        assert!(m.contains_key(&(mock_txid(2), true)));
+    });
+    assert_eq!(prune_finalized(112, 10), 2);
+    assert_eq!(prune_finalized(112, 10), 0);
+    TX_RECORDS.with_borrow(|m| {
+        assert_eq!(m.len(), 1);
+        assert!(m.contains_key(&(mock_txid(4), false)));
+    });
+    assert!(FINALIZED_TXS.with_borrow(|m| m.is_empty()));
+}
+
+#[test]
+pub fn test_prune_and_unmark_rolled_back_txs() {
+    use crate::fixtures::{mock_block, mock_txid};
+
+    BLOCKS.with_borrow_mut(|m| m.clear_new());
+    ROLLED_BACK_TXS.with_borrow_mut(|m| m.clear_new());
+    ROLLED_BACK_HEIGHTS.with_borrow_mut(|m| m.clear_new());
+
+    BLOCKS.with_borrow_mut(|m| m.insert(100, mock_block(100, vec![])));
+    mark_rolled_back(mock_txid(1));
+    BLOCKS.with_borrow_mut(|m| m.insert(105, mock_block(105, vec![])));
+    mark_rolled_back(mock_txid(2));
+    mark_rolled_back(mock_txid(2));
+    assert!(is_rolled_back(&mock_txid(1)) && is_rolled_back(&mock_txid(2)));
+    assert_eq!(ROLLED_BACK_HEIGHTS.with_borrow(|m| m.len()), 2);
+
+    // executing a rolled back tx again clears its mark
+    unmark_rolled_back(&mock_txid(2));
+    assert!(!is_rolled_back(&mock_txid(2)));
+    assert_eq!(ROLLED_BACK_HEIGHTS.with_borrow(|m| m.len()), 1);
+    mark_rolled_back(mock_txid(2));
+
+    // a mark from before the heights were kept is given the tip height
+    ROLLED_BACK_TXS.with_borrow_mut(|m| m.insert(mock_txid(3), ()));
+    backfill_rolled_back_heights();
+    assert!(ROLLED_BACK_HEIGHTS.with_borrow(|m| m.contains_key(&(105, mock_txid(3)))));
+    assert_eq!(ROLLED_BACK_HEIGHTS.with_borrow(|m| m.len()), 3);
+
+    assert_eq!(prune_rolled_back(109, 10), 0);
+    assert_eq!(prune_rolled_back(110, 10), 1);
+    assert!(!is_rolled_back(&mock_txid(1)));
+    assert!(is_rolled_back(&mock_txid(2)));
+    assert_eq!(prune_rolled_back(115, 10), 2);
+    assert!(ROLLED_BACK_TXS.with_borrow(|m| m.is_empty()));
+    assert!(ROLLED_BACK_HEIGHTS.with_borrow(|m| m.is_empty()));
+}
@@ -0,0 +1,139 @@
+
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+
+use crate::memory::{read_state, ADMIN_ACTIONS, ROLES};
+use crate::*;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Could grant and revoke roles and has every other role but `Orchestrator`, the
+    /// controllers of the canister always have it.
+    Controller,
+    /// Could drive the game lifecycle, e.g. end the game and etch the rune.
+    Operator,
+    /// Could call the REE exchange interfaces besides the configured orchestrator.
+    Orchestrator,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Roles(pub Vec<Role>);
+
+impl Storable for Roles {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct AdminAction {
+    pub caller: Principal,
+    pub action: String,
+    pub timestamp: u64,
+}
+
+impl Storable for AdminAction {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A granted `Controller` implies every other role but `Orchestrator`, and so does being a
+/// controller of the canister. The orchestrator must always be granted explicitly.
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    let granted = ROLES.with_borrow(|r| r.get(principal).unwrap_or_default()).0;
+    if role == Role::Orchestrator {
+        return granted.contains(&role);
+    }
+    granted.contains(&role)
+        || granted.contains(&Role::Controller)
+        || ic_cdk::api::is_controller(principal)
+}
+
+/// Whether the principal is the configured orchestrator or has been granted `Orchestrator`.
+pub(crate) fn is_orchestrator(principal: &Principal) -> bool {
+    read_state(|s| s.orchestrator.eq(principal)) || has_role(principal, Role::Orchestrator)
+}
+
+pub fn get_roles(principal: &Principal) -> Vec<Role> {
+    let mut roles = ROLES.with_borrow(|r| r.get(principal).unwrap_or_default()).0;
+    if ic_cdk::api::is_controller(principal) && !roles.contains(&Role::Controller) {
+        roles.insert(0, Role::Controller);
+    }
+    roles
+}
+
+pub(crate) fn grant_role(principal: Principal, role: Role) {
+    ROLES.with_borrow_mut(|r| {
+        let mut roles = r.get(&principal).unwrap_or_default();
+        if !roles.0.contains(&role) {
+            roles.0.push(role);
+        }
+        r.insert(principal, roles);
+    });
+}
+
+pub(crate) fn revoke_role(principal: Principal, role: Role) {
+    ROLES.with_borrow_mut(|r| {
+        let mut roles = r.get(&principal).unwrap_or_default();
+        roles.0.retain(|granted| *granted != role);
+        if roles.0.is_empty() {
+            r.remove(&principal);
+        } else {
+            r.insert(principal, roles);
+        }
+    });
+}
+
+pub(crate) fn record_admin_action(action: impl ToString) {
+    let action = AdminAction {
+        caller: ic_cdk::caller(),
+        action: action.to_string(),
+        timestamp: crate::utils::now_nanos(),
+    };
+    log!(INFO, "admin action by {}: {}", action.caller, action.action);
+    ADMIN_ACTIONS.with_borrow_mut(|a| {
+        let id = a.last_key_value().map(|(id, _)| id + 1).unwrap_or(0);
+        a.insert(id, action);
+    });
+}
+
+pub fn get_admin_actions(offset: u64, limit: u64) -> Vec<(u64, AdminAction)> {
+    ADMIN_ACTIONS.with_borrow(|a| {
+        a.iter()
+            .rev()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect()
+    })
+}
+
+#[test]
+pub fn test_controller_is_not_orchestrator() {
+    use crate::fixtures::mock_init_args;
+    use crate::memory::set_state;
+    use crate::state::ExchangeState;
+
+    set_state(ExchangeState::init(mock_init_args()));
+    let principal = Principal::from_slice(&[1]);
+    grant_role(principal, Role::Controller);
+    assert!(has_role(&principal, Role::Operator));
+    assert!(!has_role(&principal, Role::Orchestrator));
+    assert!(!is_orchestrator(&principal));
+
+    grant_role(principal, Role::Orchestrator);
+    assert!(is_orchestrator(&principal));
+    assert!(is_orchestrator(&Principal::anonymous()));
+}
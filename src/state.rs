@@ -1,26 +1,45 @@
-use ic_cdk::api::management_canister::bitcoin::Satoshi;
+use std::collections::VecDeque;
+
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::Storable;
 use ree_types::{CoinBalance, CoinId, InputCoin, OutputCoin};
-use std::borrow::Cow;
 
+use crate::args::{CreatePoolArgs, InitArgs, UpdatePoolArgs, UpgradeArgs};
 use crate::game::game::Game;
-use crate::memory::{read_state, GAMER};
+use crate::game::gamer::{count_gamers, get_gamer, insert_gamer, is_registered, remove_gamer};
+use crate::memory::{mutate_pool, ADDRESS_PRINCIPLE_MAP};
 use crate::utils::calculate_premine_rune_amount;
 use crate::*;
 
+#[cfg(test)]
+use crate::fixtures::{mock_gamer, mock_init_args, mock_pending_states, mock_pool, mock_txid};
+
+/// The config shared by all the pools hosted by the exchange.
 #[derive(Deserialize, Serialize, Clone, CandidType)]
 pub struct ExchangeState {
-    pub rune_name: String,
-    pub rune_id: Option<CoinId>,
-    // pub symbol: String,
-    pub key: Option<Pubkey>,
-    pub address: Option<String>,
-    pub game: Game,
+    /// The schnorr key id every pool key is derived from, fixed at init.
+    pub key_id: String,
     pub orchestrator: Principal,
-    pub states: Vec<PoolState>,
     pub ii_canister: Principal,
     pub btc_customs_principle: Principal,
+    pub network: crate::network::BtcNetwork,
+}
+
+/// A cookie game hosted by the exchange, keyed by its address in `POOLS`.
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct Pool {
+    pub rune_name: String,
+    pub rune_id: Option<CoinId>,
+    pub key: Pubkey,
+    /// The derivation path of the pool key under the key id of the exchange, unique per pool.
+    pub key_derivation_path: Vec<u8>,
+    pub address: PoolAddress,
+    pub game: Game,
+    /// The latest finalized pool state, `None` until the pool btc utxo is initialized.
+    pub confirmed_state: Option<PoolState>,
+    /// The pool states of the unfinalized txs in nonce order, each spends the utxos of the
+    /// one before it.
+    pub pending: VecDeque<PoolState>,
     pub etching_key: Option<String>,
     pub richswap_pool_address: String,
     pub game_status: GameStatus,
@@ -37,122 +56,274 @@ pub enum GameStatus {
 }
 
 impl GameStatus {
-    pub fn finish_init_key(&self) -> GameStatus {
-        match self {
+    fn invalid_transition(&self, to: &str) -> ExchangeError {
+        ExchangeError::InvalidState(format!(
+            "can't transit GameStatus from {:?} to {}",
+            self, to
+        ))
+    }
+
+    fn transit(&mut self, to: GameStatus) -> Result<()> {
+        log!(INFO, "GameStatus transit from {:?} to {:?}", self, to);
+        *self = to;
+        Ok(())
+    }
+
+    pub(crate) fn finish_init_key(&mut self) -> Result<()> {
+        let to = match *self {
             GameStatus::Initialize {
-                init_key: _,
-                init_btc,
-            } => {
-                if *init_btc {
-                    return GameStatus::Play;
-                }
-                GameStatus::Initialize {
-                    init_key: true,
-                    init_btc: false,
-                }
-            }
-            _ => panic!("GameStatus should be Initialize"),
+                init_key: false,
+                init_btc: true,
+            } => GameStatus::Play,
+            GameStatus::Initialize {
+                init_key: false,
+                init_btc: false,
+            } => GameStatus::Initialize {
+                init_key: true,
+                init_btc: false,
+            },
+            _ => return Err(self.invalid_transition("Initialize { init_key: true }")),
+        };
+        self.transit(to)
+    }
+
+    pub(crate) fn finish_init_btc(&mut self) -> Result<()> {
+        let to = match *self {
+            GameStatus::Initialize {
+                init_key: true,
+                init_btc: false,
+            } => GameStatus::Play,
+            GameStatus::Initialize {
+                init_key: false,
+                init_btc: false,
+            } => GameStatus::Initialize {
+                init_key: false,
+                init_btc: true,
+            },
+            _ => return Err(self.invalid_transition("Initialize { init_btc: true }")),
+        };
+        self.transit(to)
+    }
+
+    pub(crate) fn end(&mut self) -> Result<()> {
+        match self {
+            GameStatus::Play => self.transit(GameStatus::Ended),
+            _ => Err(self.invalid_transition("Ended")),
         }
     }
 
-    pub fn finish_init_btc(&self) -> GameStatus {
+    pub(crate) fn rune_minted(&mut self) -> Result<()> {
         match self {
-            GameStatus::Initialize {
-                init_key,
-                init_btc: _,
-            } => {
-                if *init_key {
-                    return GameStatus::Play;
-                }
-                GameStatus::Initialize {
-                    init_key: false,
-                    init_btc: true,
-                }
-            }
-            _ => panic!("GameStatus should be Initialize"),
+            GameStatus::Ended => self.transit(GameStatus::RunesMinted),
+            _ => Err(self.invalid_transition("RunesMinted")),
         }
     }
 
-    pub fn end(&self) -> GameStatus {
+    pub(crate) fn add_liquidity(&mut self) -> Result<()> {
         match self {
-            GameStatus::Play => GameStatus::Ended,
-            _ => panic!("GameStatus should be InProgress"),
+            GameStatus::RunesMinted => self.transit(GameStatus::LiquidityAdded),
+            _ => Err(self.invalid_transition("LiquidityAdded")),
         }
     }
 
-    pub fn rune_minted(&self) -> GameStatus {
+    /// The add_liquidity tx has been rolled back before it was finalized.
+    pub(crate) fn revert_add_liquidity(&mut self) -> Result<()> {
         match self {
-            GameStatus::Ended => GameStatus::RunesMinted,
-            _ => panic!("GameStatus should be Ended"),
+            GameStatus::LiquidityAdded => self.transit(GameStatus::RunesMinted),
+            _ => Err(self.invalid_transition("RunesMinted")),
         }
     }
 
-    pub fn add_liquidity(&self) -> GameStatus {
+    /// Gamers could withdraw once the add_liquidity tx has been finalized.
+    pub(crate) fn withdrawable(&mut self) -> Result<()> {
         match self {
-            GameStatus::RunesMinted => GameStatus::LiquidityAdded,
-            _ => panic!("GameStatus should be RunesMinted"),
+            GameStatus::LiquidityAdded => self.transit(GameStatus::Withdrawable),
+            _ => Err(self.invalid_transition("Withdrawable")),
         }
     }
 
-    pub fn withdrawable(&self) -> GameStatus {
+    /// What has to be done to move the game into the next phase.
+    pub fn next_step(&self) -> Option<String> {
         match self {
-            GameStatus::Ended => GameStatus::Withdrawable,
-            _ => panic!("GameStatus should be InProgress"),
+            GameStatus::Initialize { init_key, init_btc } => {
+                let mut steps = vec![];
+                if !init_key {
+                    steps.push("call create_pool to generate the pool address");
+                }
+                if !init_btc {
+                    steps.push("call init_btc_utxo with the utxo funding the pool");
+                }
+                Some(steps.join(", "))
+            }
+            GameStatus::Play => Some("call end_game to stop claiming".to_string()),
+            GameStatus::Ended => Some(
+                "call etch_rune, then update_rune_info with the premine rune utxo once etched"
+                    .to_string(),
+            ),
+            GameStatus::RunesMinted => {
+                Some("execute the add_liquidity intention to the richswap pool".to_string())
+            }
+            GameStatus::LiquidityAdded => {
+                Some("wait for the add_liquidity tx to be finalized".to_string())
+            }
+            GameStatus::Withdrawable => None,
         }
     }
 }
 
-impl Storable for ExchangeState {
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct GamePhase {
+    pub status: GameStatus,
+    pub next_step: Option<String>,
+}
+
+/// `ExchangeState` as stored in `STATE`. A state written before the pools were keyed by address
+/// decodes along with the pool it hosted, which `migrate_stable_memory` moves into the pools,
+/// the pool itself is never written back.
+#[derive(Clone)]
+pub struct StoredState {
+    pub state: ExchangeState,
+    pub legacy_pool: Option<Pool>,
+}
+
+impl Storable for StoredState {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(bincode::serialize(self).unwrap())
+        crate::migration::encode(&self.state)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        bincode::deserialize(bytes.as_ref()).unwrap()
+        crate::migration::decode_stored_state(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Pool {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        crate::migration::encode(self)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        crate::migration::decode(bytes.as_ref())
     }
 
     const BOUND: Bound = Bound::Unbounded;
 }
 
 impl ExchangeState {
-    pub fn init(
-        rune_name: String,
-        gamer_register_fee: Satoshi,
-        claim_cooling_down: Seconds,
-        cookie_amount_per_claim: u128,
-        orchestrator: Principal,
-        ii_canister: Principal,
-        btc_customs_principle: Principal,
-        richswap_pool_address: String,
-    ) -> Self {
+    pub fn init(args: InitArgs) -> Self {
+        Self {
+            key_id: args
+                .key_id
+                .unwrap_or(args.network.schnorr_key_name().to_string()),
+            orchestrator: args.orchestrator,
+            ii_canister: args.ii_canister,
+            btc_customs_principle: args.btc_customs_principle,
+            network: args.network,
+        }
+    }
+
+    /// Apply the fields changed on upgrade, the pools are updated in place in `POOLS`.
+    pub fn apply_upgrade_args(&mut self, args: UpgradeArgs) -> Result<()> {
+        for pool_args in args.pools.unwrap_or_default() {
+            let address = pool_args.pool_address.clone();
+            mutate_pool(&address, |pool| pool.apply_update(pool_args))?;
+        }
+        if let Some(orchestrator) = args.orchestrator {
+            self.orchestrator = orchestrator;
+        }
+        if let Some(ii_canister) = args.ii_canister {
+            self.ii_canister = ii_canister;
+        }
+        if let Some(blocks) = args.tx_record_retention_blocks {
+            crate::retention::set_tx_record_retention_blocks(blocks);
+        }
+        Ok(())
+    }
+}
+
+impl Pool {
+    /// A new pool whose key has been derived, it waits for the btc utxo funding it.
+    pub fn new(args: CreatePoolArgs, key: Pubkey, address: PoolAddress) -> Self {
         Self {
-            rune_id: Option::None,
-            // symbol,
-            rune_name,
-            key: None,
-            address: None,
+            key_derivation_path: args.key_derivation_path(),
+            rune_name: args.rune_name,
+            rune_id: None,
+            key,
+            address,
             game: Game::init(
-                gamer_register_fee,
-                claim_cooling_down,
-                cookie_amount_per_claim,
+                args.gamer_register_fee,
+                args.claim_cooling_down,
+                args.cookie_amount_per_claim,
             ),
-            orchestrator,
-            states: vec![],
-            ii_canister,
-            btc_customs_principle,
+            confirmed_state: None,
+            pending: VecDeque::new(),
             etching_key: None,
-            richswap_pool_address,
+            richswap_pool_address: args.richswap_pool_address,
             game_status: GameStatus::Initialize {
-                init_key: false,
+                init_key: true,
                 init_btc: false,
             },
         }
     }
 
+    /// Apply the fields changed on upgrade, each of them is only allowed in the phases
+    /// where changing it won't affect what the gamers have already done.
+    pub fn apply_update(&mut self, args: UpdatePoolArgs) -> Result<()> {
+        let before_end = matches!(
+            self.game_status,
+            GameStatus::Initialize { .. } | GameStatus::Play
+        );
+        let registered = count_gamers(&self.address) > 0;
+        let reject = |field: &str| {
+            ExchangeError::InvalidState(format!(
+                "can't change {} of pool {} in GameStatus {:?}",
+                field, self.address, self.game_status
+            ))
+        };
+
+        if let Some(gamer_register_fee) = args.gamer_register_fee {
+            (before_end && !registered)
+                .then(|| ())
+                .ok_or(reject("gamer_register_fee after registration started"))?;
+            self.game.gamer_register_fee = gamer_register_fee;
+        }
+        if let Some(claim_cooling_down) = args.claim_cooling_down {
+            before_end
+                .then(|| ())
+                .ok_or(reject("claim_cooling_down"))?;
+            self.game.claim_cooling_down = claim_cooling_down;
+        }
+        if let Some(cookie_amount_per_claim) = args.cookie_amount_per_claim {
+            before_end
+                .then(|| ())
+                .ok_or(reject("cookie_amount_per_claim"))?;
+            self.game.cookie_amount_per_claim = cookie_amount_per_claim;
+        }
+        if let Some(richswap_pool_address) = args.richswap_pool_address {
+            matches!(
+                self.game_status,
+                GameStatus::Initialize { .. }
+                    | GameStatus::Play
+                    | GameStatus::Ended
+                    | GameStatus::RunesMinted
+            )
+            .then(|| ())
+            .ok_or(reject("richswap_pool_address"))?;
+            self.richswap_pool_address = richswap_pool_address;
+        }
+        Ok(())
+    }
+
+    pub fn premine_rune_amount(&self) -> u128 {
+        calculate_premine_rune_amount(self.game.claimed_cookies)
+    }
+
     pub fn last_state(&self) -> Result<PoolState> {
         // The last state should always exist
-        self.states
-            .last()
+        self.pending
+            .back()
+            .or(self.confirmed_state.as_ref())
             .cloned()
             .ok_or(ExchangeError::LastStateNotFound)
             .inspect_err(|e| log!(ERROR, "{}", e))
@@ -160,68 +331,120 @@ impl ExchangeState {
 
     pub fn validate_withdraw(
         &self,
-        _txid: Txid,
+        txid: Txid,
         nonce: u64,
-        _pool_utxo_spend: Vec<String>,
-        _pool_utxo_receive: Vec<String>,
+        pool_utxo_spend: Vec<String>,
+        pool_utxo_receive: Vec<String>,
         input_coins: Vec<InputCoin>,
         output_coins: Vec<OutputCoin>,
         initiator_address: Address,
-    ) -> Result<(PoolState, Utxo)> {
-        assert!(
-            matches!(self.game_status, GameStatus::Withdrawable),
-            "GameStatus should be Withdrawable, but got: {:?}",
-            self.game_status
-        );
+    ) -> Result<(PoolState, Vec<Utxo>)> {
+        matches!(self.game_status, GameStatus::Withdrawable)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState(format!(
+                "GameStatus should be Withdrawable, but got: {:?}",
+                self.game_status
+            )))?;
 
-        let gamer = GAMER
-            .with_borrow(|g| g.get(&initiator_address))
+        let gamer = get_gamer(&self.address, &initiator_address)
             .ok_or(ExchangeError::GamerNotFound(initiator_address.clone()))?;
+        (!gamer.is_withdrawn)
+            .then(|| ())
+            .ok_or(ExchangeError::GamerWithdrawRepeatedly(
+                initiator_address.clone(),
+            ))?;
+        let rune_id = self.rune_id.clone().ok_or(ExchangeError::InvalidRuneId)?;
         let pool_expected_spend_rune = CoinBalance {
-            id: self.rune_id.clone().ok_or(ExchangeError::InvalidRuneId)?,
+            id: rune_id,
             value: gamer.cookies,
         };
-        assert!(
-            output_coins.len() == 1
-                && input_coins.is_empty()
-                && output_coins[0].coin.id.eq(&pool_expected_spend_rune.id)
-                && output_coins[0].coin.value == pool_expected_spend_rune.value
-                && output_coins[0].to.eq(&initiator_address),
-        );
+        (output_coins.len() == 1
+            && input_coins.is_empty()
+            && output_coins[0].coin.id.eq(&pool_expected_spend_rune.id)
+            && output_coins[0].coin.value == pool_expected_spend_rune.value
+            && output_coins[0].to.eq(&initiator_address))
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "input_coins: {:?}, output_coins: {:?}",
+                input_coins, output_coins
+            )))?;
 
-        // the pool_utxo_spend should be equal to the utxo of the last state
+        // the pool_utxo_spend should be equal to the utxos of the last state
         let last_state = self.last_state()?;
         // check nonce matches
         (last_state.nonce == nonce)
             .then(|| ())
             .ok_or(ExchangeError::PoolStateExpired(last_state.nonce))?;
+        let rune_utxo = last_state
+            .rune_utxo
+            .clone()
+            .ok_or(ExchangeError::InvalidState("rune utxo not found".to_string()))?;
+        let consumed = last_state.check_pool_utxo_spend(&pool_utxo_spend)?;
 
-        todo!()
+        let rune_balance = last_state
+            .rune_balance
+            .checked_sub(gamer.cookies)
+            .ok_or(ExchangeError::CookieBalanceInsufficient(
+                last_state.rune_balance,
+            ))?;
+
+        // the pool_utxo_receive should be [btc utxo, rune utxo]
+        (pool_utxo_receive.len() == 2)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "pool_utxo_receive should contain btc and rune utxo, got: {:?}",
+                pool_utxo_receive
+            )))?;
+        let new_utxo = Utxo::try_from(pool_utxo_receive[0].clone(), None, last_state.utxo.sats)
+            .map_err(|e| ExchangeError::InvalidSignPsbtArgs(e.to_string()))?;
+        let new_rune_utxo = Utxo::try_from(
+            pool_utxo_receive[1].clone(),
+            Some(CoinBalance {
+                id: rune_id,
+                value: rune_balance,
+            }),
+            rune_utxo.sats,
+        )
+        .map_err(|e| ExchangeError::InvalidSignPsbtArgs(e.to_string()))?;
+
+        let new_state = PoolState {
+            id: Some(txid),
+            nonce: last_state
+                .nonce
+                .checked_add(1)
+                .ok_or(ExchangeError::Overflow)?,
+            utxo: new_utxo,
+            rune_utxo: Some(new_rune_utxo),
+            rune_balance,
+            user_action: UserAction::Withdraw(initiator_address),
+        };
+
+        Ok((new_state, consumed))
     }
 
     pub fn validate_add_liquidity(
         &self,
-        _txid: Txid,
+        txid: Txid,
         nonce: u64,
         pool_utxo_spend: Vec<String>,
-        _pool_utxo_receive: Vec<String>,
+        pool_utxo_receive: Vec<String>,
         input_coins: Vec<InputCoin>,
         output_coins: Vec<OutputCoin>,
         _initiator_address: Address,
-    ) -> Result<(PoolState, Utxo)> {
-
-        assert!(
-            matches!(self.game_status, GameStatus::RunesMinted),
-            "GameStatus should be RunesMinted, but got: {:?}",
-            self.game_status
-        );
+    ) -> Result<(PoolState, Vec<Utxo>)> {
+        matches!(self.game_status, GameStatus::RunesMinted)
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState(format!(
+                "GameStatus should be RunesMinted, but got: {:?}",
+                self.game_status
+            )))?;
 
         self.game.is_end()
             .then(|| ())
             .ok_or(ExchangeError::GameNotEnd)?;
 
         // check input and output coin
-        let gamer_count = GAMER.with_borrow(|g| g.len());
+        let gamer_count = count_gamers(&self.address);
         let pool_expected_spend_btc = CoinBalance {
             id: CoinId::btc(),
             value: (gamer_count as u128) * (self.game.gamer_register_fee as u128),
@@ -229,46 +452,84 @@ impl ExchangeState {
 
         let pool_expected_spend_rune = CoinBalance {
             id: self.rune_id.clone().ok_or(ExchangeError::InvalidRuneId)?,
-            value: calculate_premine_rune_amount() - self.game.claimed_cookies,
+            value: self
+                .premine_rune_amount()
+                .checked_sub(self.game.claimed_cookies)
+                .ok_or(ExchangeError::Overflow)?,
         };
 
-        let richswap_pool_address = read_state(|s| s.richswap_pool_address.clone());
-
-        assert!(
-            output_coins.len() == 2
-                && input_coins.is_empty()
-                && output_coins[0].coin.id.eq(&pool_expected_spend_btc.id)
-                && output_coins[0].coin.value == pool_expected_spend_btc.value
-                && output_coins[0].to.eq(&richswap_pool_address)
-                && output_coins[1].coin.id.eq(&pool_expected_spend_rune.id)
-                && output_coins[1].coin.value == pool_expected_spend_rune.value
-                && output_coins[1].to.eq(&richswap_pool_address),
-            "input_coins: {:?}, output_coins: {:?}",
-            input_coins,
-            output_coins
-        );
+        let richswap_pool_address = &self.richswap_pool_address;
 
-        // the pool_utxo_spend should be equal to the utxo of the last state
+        (output_coins.len() == 2
+            && input_coins.is_empty()
+            && output_coins[0].coin.id.eq(&pool_expected_spend_btc.id)
+            && output_coins[0].coin.value == pool_expected_spend_btc.value
+            && output_coins[0].to.eq(richswap_pool_address)
+            && output_coins[1].coin.id.eq(&pool_expected_spend_rune.id)
+            && output_coins[1].coin.value == pool_expected_spend_rune.value
+            && output_coins[1].to.eq(richswap_pool_address))
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+                "input_coins: {:?}, output_coins: {:?}",
+                input_coins, output_coins
+            )))?;
+
+        // the pool_utxo_spend should be equal to the utxos of the last state
         let last_state = self.last_state()?;
         // check nonce matches
         (last_state.nonce == nonce)
             .then(|| ())
             .ok_or(ExchangeError::PoolStateExpired(last_state.nonce))?;
-        last_state
+        let rune_utxo = last_state
+            .rune_utxo
+            .clone()
+            .ok_or(ExchangeError::InvalidState("rune utxo not found".to_string()))?;
+        let consumed = last_state.check_pool_utxo_spend(&pool_utxo_spend)?;
+
+        // the btc of registration fees goes to richswap, and the runes left in pool
+        // are reserved for gamers' withdrawal
+        let btc_left = last_state
             .utxo
-            .outpoint()
-            .eq(pool_utxo_spend
-                .last()
-                .ok_or(ExchangeError::InvalidSignPsbtArgs(
-                    "pool_utxo_spend is empty".to_string(),
-                ))?)
+            .sats
+            .checked_sub(pool_expected_spend_btc.value as u64)
+            .ok_or(ExchangeError::InsufficientFunds)?;
+        let rune_balance = last_state
+            .rune_balance
+            .checked_sub(pool_expected_spend_rune.value)
+            .ok_or(ExchangeError::InsufficientFunds)?;
+
+        // the pool_utxo_receive should be [btc utxo, rune utxo]
+        (pool_utxo_receive.len() == 2)
             .then(|| ())
             .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
-                "pool_utxo_spend: {:?}, last_state_utxo: {:?}",
-                pool_utxo_spend, last_state.utxo
+                "pool_utxo_receive should contain btc and rune utxo, got: {:?}",
+                pool_utxo_receive
             )))?;
+        let new_utxo = Utxo::try_from(pool_utxo_receive[0].clone(), None, btc_left)
+            .map_err(|e| ExchangeError::InvalidSignPsbtArgs(e.to_string()))?;
+        let new_rune_utxo = Utxo::try_from(
+            pool_utxo_receive[1].clone(),
+            Some(CoinBalance {
+                id: pool_expected_spend_rune.id,
+                value: rune_balance,
+            }),
+            rune_utxo.sats,
+        )
+        .map_err(|e| ExchangeError::InvalidSignPsbtArgs(e.to_string()))?;
+
+        let new_state = PoolState {
+            id: Some(txid),
+            nonce: last_state
+                .nonce
+                .checked_add(1)
+                .ok_or(ExchangeError::Overflow)?,
+            utxo: new_utxo,
+            rune_utxo: Some(new_rune_utxo),
+            rune_balance,
+            user_action: UserAction::AddLiquidity,
+        };
 
-        todo!()
+        Ok((new_state, consumed))
     }
 
     pub fn validate_register(
@@ -280,8 +541,8 @@ impl ExchangeState {
         input_coins: Vec<InputCoin>,
         output_coins: Vec<OutputCoin>,
         address: Address,
-    ) -> Result<(PoolState, Utxo)> {
-        if GAMER.with_borrow(|g| g.contains_key(&address)) {
+    ) -> Result<(PoolState, Vec<Utxo>)> {
+        if get_gamer(&self.address, &address).is_some() {
             return Err(ExchangeError::GamerAlreadyExist(address.clone()));
         }
 
@@ -345,72 +606,201 @@ impl ExchangeState {
             user_action: UserAction::Register(address.clone()),
         };
 
-        Ok((new_state, last_state.utxo.clone()))
+        Ok((new_state, vec![last_state.utxo.clone()]))
+    }
+
+    pub(crate) fn init_btc_utxo(&mut self, utxo: Utxo) -> Result<()> {
+        self.game_status.finish_init_btc()?;
+        // the init state has no tx to wait for
+        self.confirmed_state = Some(PoolState {
+            id: None,
+            nonce: 0,
+            utxo,
+            rune_utxo: None,
+            rune_balance: 0,
+            user_action: UserAction::Init,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn end_game(&mut self) -> Result<()> {
+        self.game_status.end()?;
+        self.game.is_end = true;
+        Ok(())
+    }
+
+    pub(crate) fn add_liquidity(&mut self, state: PoolState) -> Result<()> {
+        self.game_status.add_liquidity()?;
+        self.game.add_liquidity();
+        self.commit(state);
+        Ok(())
     }
 
     pub(crate) fn commit(&mut self, state: PoolState) {
-        self.states.push(state);
+        self.pending.push_back(state);
     }
 
+    /// The last state of the pool in place, pending or finalized.
+    pub(crate) fn last_state_mut(&mut self) -> Result<&mut PoolState> {
+        self.pending
+            .back_mut()
+            .or(self.confirmed_state.as_mut())
+            .ok_or(ExchangeError::LastStateNotFound)
+    }
+
+    /// The finalized state followed by the pending ones.
+    pub fn pool_states(&self) -> Vec<PoolState> {
+        self.confirmed_state
+            .iter()
+            .chain(self.pending.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn pending_nonce(&self, txid: &Txid) -> Option<u64> {
+        self.pending
+            .iter()
+            .find(|state| state.id.as_ref() == Some(txid))
+            .map(|state| state.nonce)
+    }
+
+    /// Make the state of `txid` the confirmed state. The tx spends the utxos of the pending
+    /// states before it, so its confirmation finalizes them as well, even if their own blocks
+    /// were never received.
     pub(crate) fn finalize(&mut self, txid: Txid) -> Result<()> {
+        if self
+            .confirmed_state
+            .as_ref()
+            .is_some_and(|state| state.id == Some(txid))
+        {
+            return Ok(());
+        }
         let idx = self
-            .states
+            .pending
             .iter()
             .position(|s| s.id == Some(txid))
             .ok_or(ExchangeError::InvalidState("txid not found".to_string()))?;
 
-        if idx == 0 {
-            return Ok(());
+        if self
+            .pending
+            .range(..=idx)
+            .any(|s| matches!(s.user_action, UserAction::AddLiquidity))
+            && matches!(self.game_status, GameStatus::LiquidityAdded)
+        {
+            self.game_status.withdrawable()?;
         }
-
-        self.states.rotate_left(idx);
-        self.states.truncate(self.states.len() - idx);
+        self.confirmed_state = self.pending.drain(..=idx).last();
 
         Ok(())
     }
 
-    pub(crate) fn rollback(&mut self, txid: Txid) -> Result<()> {
-        let idx = self
-            .states
+    /// The txids of the pending pool states built on any of the `orphaned` txs, the chain of
+    /// pool utxos makes every state after the first orphaned one depend on it.
+    pub(crate) fn states_depending_on(&self, orphaned: &[Txid]) -> Vec<Txid> {
+        self.pending
             .iter()
-            .position(|state| state.id == Some(txid))
-            .ok_or(ExchangeError::InvalidState("txid not found".to_string()))?;
-        if idx == 0 {
-            // why impossible to rollback index 0 state?
-            // In init case, the state is empty, so the first state pushed in deposit interface which needn't finalize or rollback
-            // In other case, the finalize will always keep the last finalized state in vec, so the rollback should be impossible to rollback index 0 state
+            .position(|state| state.id.is_some_and(|id| orphaned.contains(&id)))
+            .map(|idx| self.pending.range(idx..).filter_map(|state| state.id).collect())
+            .unwrap_or_default()
+    }
+
+    /// Unwind the state of `txid` and every state built on it, newest first, and return
+    /// their txids. A txid without a pending state has been rolled back already or was never
+    /// executed, so there is nothing to unwind.
+    pub(crate) fn rollback(&mut self, txid: Txid) -> Result<Vec<Txid>> {
+        if self
+            .confirmed_state
+            .as_ref()
+            .is_some_and(|state| state.id == Some(txid))
+        {
             return Err(ExchangeError::InvalidState(
-                "Should not rollback index 0 state".to_string(),
+                "Should not rollback the finalized state".to_string(),
             ));
         }
-
-        while self.states.len() > idx {
-            let state = self.states.pop().ok_or(ExchangeError::InvalidState(
-                "Should not rollback index 0 state".to_string(),
+        let Some(idx) = self
+            .pending
+            .iter()
+            .position(|state| state.id == Some(txid))
+        else {
+            return Ok(vec![]);
+        };
+        // check before popping anything so that a failed rollback leaves the state untouched
+        self.pending
+            .range(idx..)
+            .all(|state| !matches!(state.user_action, UserAction::Init))
+            .then(|| ())
+            .ok_or(ExchangeError::InvalidState(
+                "Should not rollback init action".to_string(),
             ))?;
+        // the premine is computed from the claimed cookies, they are frozen once the game ended
+        if self
+            .pending
+            .range(idx..)
+            .any(|state| matches!(state.user_action, UserAction::Register(_)))
+        {
+            matches!(self.game_status, GameStatus::Play)
+                .then(|| ())
+                .ok_or(ExchangeError::InvalidState(format!(
+                    "can't rollback register in GameStatus {:?}",
+                    self.game_status
+                )))?;
+        }
+        if self
+            .pending
+            .range(idx..)
+            .any(|state| matches!(state.user_action, UserAction::AddLiquidity))
+        {
+            matches!(self.game_status, GameStatus::LiquidityAdded)
+                .then(|| ())
+                .ok_or(ExchangeError::InvalidState(format!(
+                    "can't rollback add liquidity in GameStatus {:?}",
+                    self.game_status
+                )))?;
+        }
+
+        let mut unwound = vec![];
+        while self.pending.len() > idx {
+            let state = self.pending.pop_back().expect("checked the length");
             match state.user_action {
-                UserAction::Init => {
-                    // impossible to rollback init state
-                    return Err(ExchangeError::InvalidState(
-                        "Should not rollback init action".to_string(),
-                    ));
-                }
+                UserAction::Init => unreachable!("checked above"),
                 UserAction::Register(address) => {
-                    GAMER.with_borrow_mut(|g| {
-                        g.remove(&address);
-                    });
+                    // unwind the claims made while the register tx was pending
+                    if let Some(gamer) = remove_gamer(&self.address, &address) {
+                        self.game.claimed_cookies =
+                            self.game.claimed_cookies.saturating_sub(gamer.cookies);
+                    }
+                    // the principal still claims in the other pools the address plays in
+                    if !is_registered(&address) {
+                        ADDRESS_PRINCIPLE_MAP.with_borrow_mut(|m| {
+                            let principals: Vec<Principal> = m
+                                .iter()
+                                .filter(|(_, a)| a.eq(&address))
+                                .map(|(p, _)| p)
+                                .collect();
+                            for principal in principals {
+                                m.remove(&principal);
+                            }
+                        });
+                    }
                 }
                 UserAction::Withdraw(address) => {
-                    GAMER.with_borrow_mut(|g| {
-                        let mut gamer = g.get(&address).expect("Gamer should exist in rollback");
-                        gamer.is_withdrawn = false;
-                        g.insert(address.clone(), gamer.clone());
-                    });
+                    match get_gamer(&self.address, &address) {
+                        Some(mut gamer) => {
+                            gamer.is_withdrawn = false;
+                            insert_gamer(&self.address, gamer);
+                        }
+                        None => log!(WARNING, "rollback withdraw of unknown gamer {}", address),
+                    }
+                }
+                UserAction::AddLiquidity => {
+                    self.game_status.revert_add_liquidity()?;
+                    self.game.already_add_liquidity = false;
                 }
             }
+            unwound.extend(state.id);
         }
 
-        Ok(())
+        Ok(unwound)
     }
 }
 
@@ -428,6 +818,31 @@ impl PoolState {
     pub fn btc_balance(&self) -> u64 {
         self.utxo.sats
     }
+
+    /// All the utxos held by the pool in this state, btc utxo first.
+    pub fn utxos(&self) -> Vec<Utxo> {
+        let mut utxos = vec![self.utxo.clone()];
+        if let Some(rune_utxo) = self.rune_utxo.clone() {
+            utxos.push(rune_utxo);
+        }
+        utxos
+    }
+
+    /// Check that `pool_utxo_spend` spends exactly the utxos held in this state,
+    /// and return them so that each of them can be signed.
+    pub fn check_pool_utxo_spend(&self, pool_utxo_spend: &Vec<String>) -> Result<Vec<Utxo>> {
+        let utxos = self.utxos();
+        (pool_utxo_spend.len() == utxos.len()
+            && utxos
+                .iter()
+                .all(|utxo| pool_utxo_spend.contains(&utxo.outpoint())))
+        .then(|| ())
+        .ok_or(ExchangeError::InvalidSignPsbtArgs(format!(
+            "pool_utxo_spend: {:?}, last_state_utxos: {:?}",
+            pool_utxo_spend, utxos
+        )))?;
+        Ok(utxos)
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, CandidType)]
@@ -435,6 +850,7 @@ pub enum UserAction {
     Init,
     Register(Address),
     Withdraw(Address),
+    AddLiquidity,
 }
 
 thread_local! {}
@@ -470,3 +886,431 @@ pub fn test() {
     let p_blob = Principal::from_slice(&numbers_vec);
     dbg!(&p_blob.to_text());
 }
+
+#[test]
+pub fn test_game_status_transitions() {
+    let mut status = GameStatus::Initialize {
+        init_key: false,
+        init_btc: false,
+    };
+    assert!(status.end().is_err());
+    status.finish_init_key().unwrap();
+    assert!(matches!(
+        status,
+        GameStatus::Initialize {
+            init_key: true,
+            init_btc: false
+        }
+    ));
+    assert!(status.finish_init_key().is_err());
+    status.finish_init_btc().unwrap();
+    assert!(matches!(status, GameStatus::Play));
+
+    let mut status = GameStatus::Initialize {
+        init_key: false,
+        init_btc: false,
+    };
+    status.finish_init_btc().unwrap();
+    assert!(matches!(
+        status,
+        GameStatus::Initialize {
+            init_key: false,
+            init_btc: true
+        }
+    ));
+    assert!(status.finish_init_btc().is_err());
+    status.finish_init_key().unwrap();
+    assert!(matches!(status, GameStatus::Play));
+
+    assert!(status.rune_minted().is_err());
+    status.end().unwrap();
+    assert!(matches!(status, GameStatus::Ended));
+    assert!(status.add_liquidity().is_err());
+    assert!(status.withdrawable().is_err());
+    status.rune_minted().unwrap();
+    assert!(matches!(status, GameStatus::RunesMinted));
+    assert!(status.revert_add_liquidity().is_err());
+    status.add_liquidity().unwrap();
+    assert!(matches!(status, GameStatus::LiquidityAdded));
+    status.revert_add_liquidity().unwrap();
+    assert!(matches!(status, GameStatus::RunesMinted));
+    status.add_liquidity().unwrap();
+    status.withdrawable().unwrap();
+    assert!(matches!(status, GameStatus::Withdrawable));
+    assert!(status.next_step().is_none());
+
+    for mut status in [
+        GameStatus::Play,
+        GameStatus::Ended,
+        GameStatus::RunesMinted,
+        GameStatus::LiquidityAdded,
+        GameStatus::Withdrawable,
+    ] {
+        assert!(status.finish_init_key().is_err());
+        assert!(status.finish_init_btc().is_err());
+    }
+    let mut status = GameStatus::Withdrawable;
+    assert!(status.end().is_err());
+    assert!(status.rune_minted().is_err());
+    assert!(status.add_liquidity().is_err());
+    assert!(status.withdrawable().is_err());
+    assert!(matches!(status, GameStatus::Withdrawable));
+}
+
+#[test]
+pub fn test_apply_upgrade_args() {
+    use crate::memory::{clear_pools, get_pool, insert_pool};
+
+    crate::memory::GAMER.with_borrow_mut(|m| m.clear_new());
+    clear_pools();
+    let mut state = ExchangeState::init(mock_init_args());
+    assert_eq!(state.key_id, "test_key_1");
+    let pool = mock_pool("pool");
+    assert_eq!(pool.key_derivation_path, b"COOKIE".to_vec());
+    assert!(matches!(
+        pool.game_status,
+        GameStatus::Initialize {
+            init_key: true,
+            init_btc: false
+        }
+    ));
+    insert_pool(pool);
+
+    let update = |args: UpdatePoolArgs| UpgradeArgs {
+        pools: Some(vec![UpdatePoolArgs {
+            pool_address: "pool".to_string(),
+            ..args
+        }]),
+        ..Default::default()
+    };
+    state
+        .apply_upgrade_args(update(UpdatePoolArgs {
+            gamer_register_fee: Some(20000),
+            claim_cooling_down: Some(30),
+            ..Default::default()
+        }))
+        .unwrap();
+    assert_eq!(get_pool("pool").unwrap().game.gamer_register_fee, 20000);
+    assert_eq!(get_pool("pool").unwrap().game.claim_cooling_down, 30);
+
+    // a pool of another canister can't be updated
+    assert!(matches!(
+        state.apply_upgrade_args(UpgradeArgs {
+            pools: Some(vec![UpdatePoolArgs {
+                pool_address: "unknown".to_string(),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }),
+        Err(ExchangeError::PoolNotFound(_))
+    ));
+
+    mutate_pool("pool", |pool| {
+        pool.game_status = GameStatus::Ended;
+        Ok(())
+    })
+    .unwrap();
+    assert!(state
+        .apply_upgrade_args(update(UpdatePoolArgs {
+            cookie_amount_per_claim: Some(1),
+            ..Default::default()
+        }))
+        .is_err());
+    assert_eq!(get_pool("pool").unwrap().game.cookie_amount_per_claim, 100);
+    state
+        .apply_upgrade_args(UpgradeArgs {
+            orchestrator: Some(Principal::management_canister()),
+            ..Default::default()
+        })
+        .unwrap();
+    assert_eq!(state.orchestrator, Principal::management_canister());
+
+    mutate_pool("pool", |pool| {
+        pool.game_status = GameStatus::Withdrawable;
+        Ok(())
+    })
+    .unwrap();
+    assert!(state
+        .apply_upgrade_args(update(UpdatePoolArgs {
+            richswap_pool_address: Some("pool".to_string()),
+            ..Default::default()
+        }))
+        .is_err());
+}
+
+#[test]
+pub fn test_rollback_unknown_txid_is_noop() {
+    let mut pool = mock_pending_states(vec![UserAction::Register("a".to_string())]);
+    mock_gamer(&mut pool, "a", 1, 100);
+
+    assert_eq!(pool.rollback(mock_txid(7)).unwrap(), vec![]);
+    assert_eq!(pool.pending.len(), 1);
+    assert_eq!(pool.game.claimed_cookies, 100);
+    assert!(get_gamer("pool", "a").is_some());
+}
+
+#[test]
+pub fn test_rollback_register_unwinds_gamer_and_claims() {
+    let mut pool = mock_pending_states(vec![
+        UserAction::Register("a".to_string()),
+        UserAction::Register("b".to_string()),
+    ]);
+    mock_gamer(&mut pool, "a", 1, 100);
+    mock_gamer(&mut pool, "b", 2, 300);
+
+    assert_eq!(pool.rollback(mock_txid(2)).unwrap(), vec![mock_txid(2)]);
+    assert_eq!(pool.pending.len(), 1);
+    assert_eq!(pool.game.claimed_cookies, 100);
+    assert!(get_gamer("pool", "a").is_some());
+    assert!(get_gamer("pool", "b").is_none());
+    assert!(ADDRESS_PRINCIPLE_MAP.with_borrow(|m| m.contains_key(&Principal::from_slice(&[1]))));
+    assert!(!ADDRESS_PRINCIPLE_MAP.with_borrow(|m| m.contains_key(&Principal::from_slice(&[2]))));
+
+    // rolling back again changes nothing
+    assert_eq!(pool.rollback(mock_txid(2)).unwrap(), vec![]);
+    assert_eq!(pool.game.claimed_cookies, 100);
+}
+
+#[test]
+pub fn test_rollback_register_rejected_after_game_ended() {
+    let mut pool = mock_pending_states(vec![UserAction::Register("a".to_string())]);
+    mock_gamer(&mut pool, "a", 1, 100);
+    let premine = pool.premine_rune_amount();
+
+    for status in [GameStatus::Ended, GameStatus::RunesMinted] {
+        pool.game_status = status;
+        assert!(pool.rollback(mock_txid(1)).is_err());
+        assert_eq!(pool.pending.len(), 1);
+        assert_eq!(pool.game.claimed_cookies, 100);
+        assert_eq!(pool.premine_rune_amount(), premine);
+        assert!(get_gamer("pool", "a").is_some());
+    }
+}
+
+#[test]
+pub fn test_rollback_register_keeps_gamer_of_other_pools() {
+    let mut pool = mock_pending_states(vec![UserAction::Register("a".to_string())]);
+    mock_gamer(&mut pool, "a", 1, 100);
+    let mut other = mock_pool("other");
+    mock_gamer(&mut other, "a", 1, 300);
+    crate::memory::insert_pool(pool.clone());
+    crate::memory::insert_pool(other);
+    assert_eq!(count_gamers("pool"), 1);
+    assert_eq!(count_gamers("other"), 1);
+
+    assert_eq!(pool.rollback(mock_txid(1)).unwrap(), vec![mock_txid(1)]);
+    assert_eq!(pool.game.claimed_cookies, 0);
+    assert_eq!(count_gamers("pool"), 0);
+    assert_eq!(get_gamer("other", "a").unwrap().cookies, 300);
+    // the principal still claims in the other pool
+    assert!(ADDRESS_PRINCIPLE_MAP.with_borrow(|m| m.contains_key(&Principal::from_slice(&[1]))));
+}
+
+#[test]
+pub fn test_rollback_unwinds_descendant_states() {
+    let mut pool = mock_pending_states(vec![
+        UserAction::Register("a".to_string()),
+        UserAction::Register("b".to_string()),
+        UserAction::Register("c".to_string()),
+        UserAction::Register("d".to_string()),
+    ]);
+    for (i, address) in ["a", "b", "c", "d"].into_iter().enumerate() {
+        mock_gamer(&mut pool, address, i as u8 + 1, 100);
+    }
+
+    assert_eq!(
+        pool.rollback(mock_txid(2)).unwrap(),
+        vec![mock_txid(4), mock_txid(3), mock_txid(2)]
+    );
+    assert_eq!(pool.pending.len(), 1);
+    assert_eq!(pool.game.claimed_cookies, 100);
+    assert_eq!(count_gamers("pool"), 1);
+    assert_eq!(ADDRESS_PRINCIPLE_MAP.with_borrow(|m| m.len()), 1);
+
+    // the orchestrator rolls back the descendants as well
+    for i in 2..=4 {
+        assert_eq!(pool.rollback(mock_txid(i)).unwrap(), vec![]);
+    }
+    assert_eq!(pool.pending.len(), 1);
+    assert_eq!(pool.game.claimed_cookies, 100);
+}
+
+#[test]
+pub fn test_rollback_withdraw_and_add_liquidity() {
+    let mut pool = mock_pending_states(vec![
+        UserAction::AddLiquidity,
+        UserAction::Withdraw("a".to_string()),
+        UserAction::Withdraw("ghost".to_string()),
+    ]);
+    mock_gamer(&mut pool, "a", 1, 100);
+    let mut gamer = get_gamer("pool", "a").unwrap();
+    gamer.is_withdrawn = true;
+    insert_gamer("pool", gamer);
+    pool.game.already_add_liquidity = true;
+
+    // the add liquidity state can't be unwound before liquidity is added
+    pool.game_status = GameStatus::RunesMinted;
+    assert!(pool.rollback(mock_txid(1)).is_err());
+    assert_eq!(pool.pending.len(), 3);
+    assert!(get_gamer("pool", "a").unwrap().is_withdrawn);
+
+    pool.game_status = GameStatus::LiquidityAdded;
+    assert_eq!(pool.rollback(mock_txid(3)).unwrap(), vec![mock_txid(3)]);
+    assert_eq!(pool.rollback(mock_txid(2)).unwrap(), vec![mock_txid(2)]);
+    assert!(!get_gamer("pool", "a").unwrap().is_withdrawn);
+    assert!(matches!(pool.game_status, GameStatus::LiquidityAdded));
+
+    assert_eq!(pool.rollback(mock_txid(1)).unwrap(), vec![mock_txid(1)]);
+    assert!(matches!(pool.game_status, GameStatus::RunesMinted));
+    assert!(!pool.game.already_add_liquidity);
+    assert_eq!(pool.pending.len(), 0);
+    assert_eq!(pool.game.claimed_cookies, 100);
+}
+
+#[test]
+pub fn test_rollback_rejects_finalized_and_init_states() {
+    let mut pool = mock_pending_states(vec![UserAction::Register("a".to_string())]);
+    mock_gamer(&mut pool, "a", 1, 100);
+    pool.finalize(mock_txid(1)).unwrap();
+    assert_eq!(pool.pending.len(), 0);
+    assert!(pool.rollback(mock_txid(1)).is_err());
+    assert!(get_gamer("pool", "a").is_some());
+
+    let mut pool = mock_pending_states(vec![
+        UserAction::Register("a".to_string()),
+        UserAction::Init,
+    ]);
+    mock_gamer(&mut pool, "a", 1, 100);
+    assert!(pool.rollback(mock_txid(1)).is_err());
+    assert_eq!(pool.pending.len(), 2);
+    assert_eq!(pool.game.claimed_cookies, 100);
+    assert!(get_gamer("pool", "a").is_some());
+}
+
+#[test]
+pub fn test_finalize_drains_the_states_before_it() {
+    let mut pool = mock_pending_states(vec![
+        UserAction::Register("a".to_string()),
+        UserAction::Register("b".to_string()),
+        UserAction::Register("c".to_string()),
+    ]);
+
+    // the tx 1 was confirmed in a block that never arrived, the tx 2 spends its utxo
+    pool.finalize(mock_txid(2)).unwrap();
+    assert_eq!(pool.confirmed_state.as_ref().unwrap().id, Some(mock_txid(2)));
+    assert_eq!(
+        pool.pending.iter().map(|s| s.id.unwrap()).collect::<Vec<_>>(),
+        vec![mock_txid(3)]
+    );
+    assert!(pool.finalize(mock_txid(2)).is_ok());
+    assert!(pool.finalize(mock_txid(1)).is_err());
+    assert!(pool.rollback(mock_txid(1)).is_err());
+
+    pool.finalize(mock_txid(3)).unwrap();
+    assert!(pool.pending.is_empty());
+
+    let mut pool = mock_pending_states(vec![
+        UserAction::AddLiquidity,
+        UserAction::Withdraw("a".to_string()),
+    ]);
+    pool.game_status = GameStatus::LiquidityAdded;
+    pool.finalize(mock_txid(2)).unwrap();
+    assert!(matches!(pool.game_status, GameStatus::Withdrawable));
+}
+
+#[cfg(test)]
+#[derive(Clone, Debug)]
+enum PoolOp {
+    Commit,
+    Finalize(usize),
+    Rollback(usize),
+}
+
+#[cfg(test)]
+fn pool_op() -> impl proptest::strategy::Strategy<Value = PoolOp> {
+    use proptest::prelude::*;
+    prop_oneof![
+        2 => Just(PoolOp::Commit),
+        1 => any::<usize>().prop_map(PoolOp::Finalize),
+        1 => any::<usize>().prop_map(PoolOp::Rollback),
+    ]
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_random_commit_finalize_rollback(ops in proptest::collection::vec(pool_op(), 1..60)) {
+        use std::collections::BTreeSet;
+
+        let mut pool = mock_pending_states(vec![]);
+        // the model: the pending txids in nonce order, the finalized txid and the live gamers
+        let mut pending: Vec<u32> = vec![];
+        let mut confirmed = 0u32;
+        let mut live = BTreeSet::new();
+        let mut next = 1u32;
+
+        for op in ops {
+            match op {
+                PoolOp::Commit => {
+                    let nonce = pool.last_state().unwrap().nonce + 1;
+                    let address = format!("gamer_{}", next);
+                    mock_gamer(&mut pool, &address, next as u8, next as u128);
+                    pool.commit(PoolState {
+                        id: Some(mock_txid(next)),
+                        nonce,
+                        utxo: Utxo::try_from(format!("{}:0", mock_txid(next)), None, 10000)
+                            .unwrap(),
+                        rune_utxo: None,
+                        rune_balance: 0,
+                        user_action: UserAction::Register(address),
+                    });
+                    pending.push(next);
+                    live.insert(next);
+                    next += 1;
+                }
+                PoolOp::Finalize(i) => {
+                    if pending.is_empty() {
+                        if confirmed > 0 {
+                            assert!(pool.finalize(mock_txid(confirmed)).is_ok());
+                        }
+                        continue;
+                    }
+                    let k = i % pending.len();
+                    pool.finalize(mock_txid(pending[k])).unwrap();
+                    confirmed = pending.drain(..=k).last().unwrap();
+                    assert!(pool.finalize(mock_txid(confirmed)).is_ok());
+                }
+                PoolOp::Rollback(i) => {
+                    if pending.is_empty() {
+                        if confirmed > 0 {
+                            assert!(pool.rollback(mock_txid(confirmed)).is_err());
+                        }
+                        continue;
+                    }
+                    let k = i % pending.len();
+                    let txid = mock_txid(pending[k]);
+                    let expected: Vec<Txid> =
+                        pending[k..].iter().rev().map(|i| mock_txid(*i)).collect();
+                    assert_eq!(pool.rollback(txid).unwrap(), expected);
+                    assert_eq!(pool.rollback(txid).unwrap(), vec![]);
+                    for i in pending.drain(k..) {
+                        live.remove(&i);
+                    }
+                }
+            }
+
+            let confirmed_state = pool.confirmed_state.clone().unwrap();
+            assert_eq!(confirmed_state.id, (confirmed > 0).then(|| mock_txid(confirmed)));
+            assert_eq!(
+                pool.pending.iter().map(|s| s.id.unwrap()).collect::<Vec<_>>(),
+                pending.iter().map(|i| mock_txid(*i)).collect::<Vec<_>>()
+            );
+            for (j, s) in pool.pending.iter().enumerate() {
+                assert_eq!(s.nonce, confirmed_state.nonce + 1 + j as u64);
+            }
+            assert_eq!(pool.game.claimed_cookies, live.iter().map(|i| *i as u128).sum::<u128>());
+            assert_eq!(count_gamers("pool"), live.len() as u64);
+            assert_eq!(ADDRESS_PRINCIPLE_MAP.with_borrow(|m| m.len()), live.len() as u64);
+        }
+    }
+}
@@ -1,7 +1,12 @@
-use ic_cdk::api::management_canister::bitcoin::{BitcoinNetwork, Satoshi};
+use ic_cdk::api::management_canister::bitcoin::{
+    bitcoin_get_utxos, BitcoinNetwork, GetUtxosRequest, Satoshi, UtxoFilter,
+};
 use ree_types::bitcoin::key::{Secp256k1, TapTweak, TweakedPublicKey};
+use ree_types::bitcoin::Network;
 
-use crate::{memory::read_state, *};
+use crate::{external::rune_indexer::Result_ as RuneBalancesResult, memory::read_state, *};
+use ree_types::{CoinBalance, CoinId};
+use std::str::FromStr;
 
 pub(crate) fn tweak_pubkey_with_empty(untweaked: Pubkey) -> TweakedPublicKey {
     let secp = Secp256k1::new();
@@ -9,8 +14,151 @@ pub(crate) fn tweak_pubkey_with_empty(untweaked: Pubkey) -> TweakedPublicKey {
     tweaked
 }
 
+pub(crate) fn get_bitcoin_network() -> Network {
+    read_state(|s| s.network.bitcoin_network())
+}
+
+pub(crate) fn get_rune_indexer() -> RuneIndexer {
+    RuneIndexer(read_state(|s| s.network.rune_indexer()))
+}
+
+
+/// Look up the rune etched by `etching_key` from the rune indexer, the etching must have
+/// at least `MIN_ETCHING_CONFIRMATIONS`.
+pub(crate) async fn fetch_etched_rune_id(etching_key: String) -> Result<CoinId> {
+    let etching = get_rune_indexer()
+        .get_etching(etching_key.clone())
+        .await
+        .map_err(|(code, msg)| ExchangeError::FetchRuneIndexerError(code, msg))?
+        .0
+        .ok_or(ExchangeError::RuneIndexerResultError(format!(
+            "etching {} not found",
+            etching_key
+        )))?;
+    (etching.confirmations >= MIN_ETCHING_CONFIRMATIONS)
+        .then(|| ())
+        .ok_or(ExchangeError::RuneIndexerResultError(format!(
+            "etching {} has {} confirmations, {} required",
+            etching_key, etching.confirmations, MIN_ETCHING_CONFIRMATIONS
+        )))?;
+    CoinId::from_str(&etching.rune_id).map_err(|_| ExchangeError::InvalidRuneId)
+}
+
+/// The balances of `rune_id` held by each of `outpoints`, in the same order.
+pub(crate) async fn fetch_rune_balances(outpoints: Vec<String>, rune_id: CoinId) -> Result<Vec<u128>> {
+    let balances = match get_rune_indexer()
+        .get_rune_balances_for_outputs(outpoints)
+        .await
+        .map_err(|(code, msg)| ExchangeError::FetchRuneIndexerError(code, msg))?
+        .0
+    {
+        RuneBalancesResult::Ok(balances) => balances,
+        RuneBalancesResult::Err(e) => {
+            return Err(ExchangeError::RuneIndexerResultError(format!("{:?}", e)))
+        }
+    };
+    balances
+        .into_iter()
+        .map(|maybe_balances| {
+            maybe_balances
+                .unwrap_or_default()
+                .into_iter()
+                .find(|b| CoinId::from_str(&b.rune_id).ok() == Some(rune_id))
+                .map(|b| {
+                    u128::try_from(&b.amount.0)
+                        .map_err(|_| ExchangeError::NatConvertError(b.amount.clone()))
+                })
+                .unwrap_or(Ok(0))
+        })
+        .collect()
+}
+
+/// Look up the etching and the rune balance of `outpoint` from the rune indexer.
+pub(crate) async fn fetch_premine_rune_balance(
+    etching_key: String,
+    outpoint: String,
+) -> Result<CoinBalance> {
+    let rune_id = fetch_etched_rune_id(etching_key).await?;
+    let value = fetch_rune_balances(vec![outpoint.clone()], rune_id)
+        .await?
+        .into_iter()
+        .next()
+        .filter(|value| *value > 0)
+        .ok_or(ExchangeError::ParseUtxoRuneBalanceError(format!(
+            "{} holds no {}",
+            outpoint, rune_id
+        )))?;
+
+    Ok(CoinBalance { id: rune_id, value })
+}
+
+/// The utxos of `address` with at least `min_confirmations`, as outpoints with their sats.
+/// Every page of the bitcoin canister is read, since an address could hold more utxos than
+/// fit in one.
+pub(crate) async fn fetch_confirmed_utxos(
+    address: &str,
+    min_confirmations: u32,
+) -> Result<Vec<(String, Satoshi)>> {
+    let network = read_state(|s| s.network.management_network())?;
+    let mut filter = Some(UtxoFilter::MinConfirmations(min_confirmations));
+    let mut tip_height = None;
+    let mut utxos = vec![];
+    loop {
+        let (response,) = bitcoin_get_utxos(GetUtxosRequest {
+            address: address.to_string(),
+            network,
+            filter,
+        })
+        .await
+        .map_err(|(code, msg)| ExchangeError::CustomError(format!("{:?}: {}", code, msg)))?;
+        // the pages after the first are requested by their token, so the confirmations are
+        // checked against the tip of the first page
+        let tip_height = *tip_height.get_or_insert(response.tip_height);
+        utxos.extend(
+            response
+                .utxos
+                .into_iter()
+                .filter(|utxo| (tip_height + 1).saturating_sub(utxo.height) >= min_confirmations)
+                .map(|utxo| {
+                    let mut txid = utxo.outpoint.txid.clone();
+                    txid.reverse();
+                    (
+                        format!("{}:{}", hex::encode(txid), utxo.outpoint.vout),
+                        utxo.value,
+                    )
+                }),
+        );
+        match response.next_page {
+            Some(page) => filter = Some(UtxoFilter::Page(page)),
+            None => return Ok(utxos),
+        }
+    }
+}
+
 pub(crate) fn get_chain_second_timestamp()-> SecondTimestamp {
-    ic_cdk::api::time() / 1000_000_000
+    now_nanos() / 1000_000_000
+}
+
+/// The IC time in nanoseconds.
+#[cfg(not(test))]
+pub(crate) fn now_nanos() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_TIME: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// `ic_cdk::api::time` traps off the IC, the unit tests read the time set by `set_test_time`.
+#[cfg(test)]
+pub(crate) fn now_nanos() -> u64 {
+    TEST_TIME.get()
+}
+
+#[cfg(test)]
+pub(crate) fn set_test_time(nanos: u64) {
+    TEST_TIME.set(nanos);
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
@@ -53,10 +201,9 @@ pub fn get_max_recoverable_reorg_depth(network: BitcoinNetwork) -> u32 {
     }
 }
 
-pub fn calculate_premine_rune_amount()->u128{
-    read_state(|s| {
-        s.game.claimed_cookies * 120 / 100
-    })
+/// The runes premined for the claimed cookies of a pool, with a fifth on top for the liquidity.
+pub fn calculate_premine_rune_amount(claimed_cookies: u128) -> u128 {
+    claimed_cookies * 120 / 100
 }
 
 